@@ -127,6 +127,7 @@ struct Context<W> {
     includes: Vec<String>,
     included: HashSet<PathBuf>,
     events: Vec<spec::Event>,
+    commands: Vec<(String, String)>,
     unions: BTreeMap<String, spec::CombinedUnion>,
     enums: BTreeMap<String, spec::Enum>,
     types: BTreeMap<String, spec::Struct>,
@@ -141,6 +142,7 @@ impl<W: Write> Context<W> {
             includes: Default::default(),
             included: Default::default(),
             events: Default::default(),
+            commands: Default::default(),
             unions: Default::default(),
             enums: Default::default(),
             types: Default::default(),
@@ -157,6 +159,7 @@ impl<W: Write> Context<W> {
             },
             Spec::Command(v) => {
                 let type_id = type_identifier(&v.id);
+                self.commands.push((v.id.clone(), type_id.clone()));
                 match v.data {
                     spec::DataOrType::Type(ref ty) if type_identifier(&ty.name) == type_id => (),
                     ty => {
@@ -205,6 +208,9 @@ impl ::qapi_spec::Command for {} {{
                     writeln!(self.out, "::qapi_spec::Empty;")
                 }?;
                 writeln!(self.out, "}}")?;
+                if v.allow_oob {
+                    writeln!(self.out, "impl ::qapi_spec::OobCommand for {} {{ }}", type_id)?;
+                }
             },
             Spec::Struct(v) => {
                 self.types.insert(v.id.clone(), v);
@@ -622,7 +628,71 @@ impl Event {{
         writeln!(self.out, "
         }}
     }}
+
+    pub fn name(&self) -> &'static str {{
+        match *self {{")?;
+        for event in &self.events {
+            let id = event_identifier(&event.id);
+            writeln!(self.out, "Event::{} {{ .. }} => <{} as ::qapi_spec::Event>::NAME,", id, id)?;
+        }
+        writeln!(self.out, "
+        }}
+    }}
 }}")?;
+
+        for event in &self.events {
+            let id = event_identifier(&event.id);
+            writeln!(self.out, "
+impl ::core::convert::TryFrom<Event> for {} {{
+    type Error = Event;
+
+    #[allow(unreachable_patterns)]
+    fn try_from(e: Event) -> Result<Self, Self::Error> {{
+        match e {{
+            Event::{} {{ data, .. }} => Ok(data),
+            e => Err(e),
+        }}
+    }}
+}}", id, id)?;
+        }
+        Ok(())
+    }
+
+    fn process_commands(&mut self) -> io::Result<()> {
+        writeln!(self.out, "
+/// The server side of each command, which fails with `CommandNotFound` unless implemented
+pub trait {}Handler {{", self.command_trait)?;
+        for (_, type_id) in &self.commands {
+            writeln!(self.out, "
+    fn {}(&mut self, _command: {}) -> ::qapi_spec::CommandResult<{}> {{
+        Err(::qapi_spec::Error::command_not_found(<{} as ::qapi_spec::Command>::NAME))
+    }}", type_id, type_id, type_id, type_id)?;
+        }
+        writeln!(self.out, "
+    /// Decodes the arguments of the command `name` and encodes its result
+    fn dispatch(&mut self, name: &str, arguments: ::qapi_spec::Any) -> Result<::qapi_spec::Any, ::qapi_spec::Error> {{
+        match name {{")?;
+        for (name, type_id) in &self.commands {
+            writeln!(self.out, "\t\t\t\"{}\" => ::qapi_spec::handle_command(arguments, |c: {}| self.{}(c)),", name, type_id, type_id)?;
+        }
+        writeln!(self.out, "
+            name => Err(::qapi_spec::Error::command_not_found(name)),
+        }}
+    }}
+}}")?;
+
+        writeln!(self.out, "
+/// Whether the command `name` may be sent via `exec-oob`
+pub fn allows_oob(name: &str) -> bool {{
+    match name {{")?;
+        for (name, type_id) in &self.commands {
+            writeln!(self.out, "\t\t\"{}\" => <{} as ::qapi_spec::Command>::ALLOW_OOB,", name, type_id)?;
+        }
+        writeln!(self.out, "
+        _ => false,
+    }}
+}}")?;
+
         Ok(())
     }
 }
@@ -658,6 +728,7 @@ pub fn codegen<S: AsRef<Path>, O: AsRef<Path>>(schema_path: S, out_path: O, comm
         context.process_unions()?;
         context.process_structs()?;
         context.process_events()?;
+        context.process_commands()?;
         Ok(context.included)
     }
 }
@@ -0,0 +1,192 @@
+use std::io::{self, BufRead, Write};
+use std::time::{Duration, Instant};
+use log::warn;
+use qapi_qga::{guest_fsfreeze_freeze, guest_fsfreeze_freeze_list, guest_fsfreeze_thaw, guest_fsfreeze_status, GuestFsfreezeStatus};
+use crate::{Qga, ExecuteError};
+
+/// The guest thaws itself once a freeze times out, which only shows in its status
+pub(crate) fn fsfreeze_remaining(status: GuestFsfreezeStatus, frozen: i64) -> i64 {
+    match status {
+        GuestFsfreezeStatus::frozen => frozen,
+        GuestFsfreezeStatus::thawed => 0,
+    }
+}
+
+pub(crate) fn fsfreeze_timeout(elapsed: Duration) -> ExecuteError {
+    io::Error::new(io::ErrorKind::TimedOut, format!("guest filesystems were frozen for {:?}, exceeding the freeze timeout", elapsed)).into()
+}
+
+/// Keeps guest filesystems frozen until it is dropped or [`thaw`](Self::thaw)ed
+pub struct QgaFreezeGuard<'a, S: BufRead + Write> {
+    qga: &'a mut Qga<S>,
+    frozen: i64,
+    started: Instant,
+    thawed: bool,
+}
+
+impl<'a, S: BufRead + Write> QgaFreezeGuard<'a, S> {
+    /// The number of filesystems that were frozen
+    pub fn frozen(&self) -> i64 {
+        self.frozen
+    }
+
+    /// How long the filesystems have been frozen for
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The agent, which may still be used while the guest is frozen
+    pub fn qga(&mut self) -> &mut Qga<S> {
+        self.qga
+    }
+
+    pub fn status(&mut self) -> Result<GuestFsfreezeStatus, ExecuteError> {
+        self.qga.execute(&guest_fsfreeze_status { })
+    }
+
+    /// Queries `guest-fsfreeze-status` for the number of filesystems that are still frozen
+    ///
+    /// This is the count from the freeze while the guest reports itself frozen, and 0 once it has
+    /// thawed on its own, as Windows guests do after about 10 seconds.
+    pub fn frozen_status(&mut self) -> Result<i64, ExecuteError> {
+        let frozen = self.frozen;
+        self.status().map(|status| fsfreeze_remaining(status, frozen))
+    }
+
+    /// Returns the number of filesystems that were thawed
+    pub fn thaw(mut self) -> Result<i64, ExecuteError> {
+        self.thawed = true;
+        self.qga.execute(&guest_fsfreeze_thaw { })
+    }
+}
+
+impl<'a, S: BufRead + Write> Drop for QgaFreezeGuard<'a, S> {
+    fn drop(&mut self) {
+        if !self.thawed {
+            if let Err(e) = self.qga.execute(&guest_fsfreeze_thaw { }) {
+                warn!("failed to thaw guest filesystems: {}", e);
+            }
+        }
+    }
+}
+
+impl<S: BufRead + Write> Qga<S> {
+    /// Freezes the guest's filesystems, or only those at `mountpoints`
+    pub fn fsfreeze(&mut self, mountpoints: Option<Vec<String>>) -> Result<QgaFreezeGuard<'_, S>, ExecuteError> {
+        let frozen = match mountpoints {
+            None => self.execute(&guest_fsfreeze_freeze { })?,
+            Some(mountpoints) => self.execute(&guest_fsfreeze_freeze_list {
+                mountpoints: Some(mountpoints),
+            })?,
+        };
+
+        Ok(QgaFreezeGuard {
+            qga: self,
+            frozen,
+            started: Instant::now(),
+            thawed: false,
+        })
+    }
+
+    /// Runs `scope` while the guest's filesystems are frozen, then always thaws them
+    ///
+    /// `scope` is given the number of frozen filesystems. It cannot be interrupted, so if it runs
+    /// for longer than `timeout` its result is replaced by a `TimedOut` error, as the guest may have
+    /// given up on the freeze by then. Windows guests thaw themselves after about 10 seconds.
+    pub fn with_fsfreeze<T, E, F>(&mut self, mountpoints: Option<Vec<String>>, timeout: Option<Duration>, scope: F) -> Result<T, E> where
+        F: FnOnce(&mut Qga<S>, i64) -> Result<T, E>,
+        E: From<ExecuteError>,
+    {
+        let mut guard = self.fsfreeze(mountpoints)?;
+        let frozen = guard.frozen();
+        let res = scope(guard.qga(), frozen);
+        let elapsed = guard.elapsed();
+        let thawed = guard.thaw();
+
+        let res = match timeout {
+            Some(timeout) if elapsed > timeout && res.is_ok() => Err(fsfreeze_timeout(elapsed).into()),
+            _ => res,
+        };
+        match (res, thawed) {
+            (Ok(res), Ok(..)) => Ok(res),
+            (Ok(..), Err(e)) => Err(e.into()),
+            (Err(e), thawed) => {
+                if let Err(thaw) = thawed {
+                    warn!("failed to thaw guest filesystems: {}", thaw);
+                }
+                Err(e)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::{io, thread};
+    use std::time::Duration;
+    use crate::{Qga, QapiReplay, ExecuteError};
+
+    /// Freezes two filesystems, then expects them to be thawed
+    pub(crate) const TRANSCRIPT: &str = r#"
+{"elapsed":0.0,"direction":"sent","line":{"execute":"guest-fsfreeze-freeze","arguments":{}}}
+{"elapsed":0.0,"direction":"received","line":{"return":2}}
+{"elapsed":0.1,"direction":"sent","line":{"execute":"guest-fsfreeze-thaw","arguments":{}}}
+{"elapsed":0.1,"direction":"received","line":{"return":2}}
+"#;
+
+    fn replay() -> (QapiReplay, Qga<crate::Stream<io::BufReader<QapiReplay>, QapiReplay>>) {
+        let replay = QapiReplay::from_reader(TRANSCRIPT.as_bytes()).unwrap();
+        (replay.clone(), Qga::from_stream(replay))
+    }
+
+    #[test]
+    fn thaw_on_drop() {
+        let (replay, mut qga) = replay();
+        let guard = qga.fsfreeze(None).unwrap();
+        assert_eq!(guard.frozen(), 2);
+        drop(guard);
+        replay.finish().unwrap();
+    }
+
+    #[test]
+    fn thaw_on_error() {
+        let (replay, mut qga) = replay();
+        let res = qga.with_fsfreeze(None, None, |_, frozen| -> Result<(), ExecuteError> {
+            assert_eq!(frozen, 2);
+            Err(io::Error::other("snapshot failed").into())
+        });
+        assert_eq!(res.unwrap_err().to_string(), "snapshot failed");
+        replay.finish().unwrap();
+    }
+
+    #[test]
+    fn thaw_on_timeout() {
+        let (replay, mut qga) = replay();
+        let res = qga.with_fsfreeze(None, Some(Duration::ZERO), |_, _| -> Result<(), ExecuteError> {
+            thread::sleep(Duration::from_millis(1));
+            Ok(())
+        });
+        assert_eq!(io::Error::from(res.unwrap_err()).kind(), io::ErrorKind::TimedOut);
+        replay.finish().unwrap();
+    }
+
+    #[test]
+    fn frozen_status() {
+        let replay = QapiReplay::from_reader(concat!(
+            r#"{"elapsed":0.0,"direction":"sent","line":{"execute":"guest-fsfreeze-freeze","arguments":{}}}"#, "\n",
+            r#"{"elapsed":0.0,"direction":"received","line":{"return":2}}"#, "\n",
+            r#"{"elapsed":0.1,"direction":"sent","line":{"execute":"guest-fsfreeze-status","arguments":{}}}"#, "\n",
+            r#"{"elapsed":0.1,"direction":"received","line":{"return":"frozen"}}"#, "\n",
+            r#"{"elapsed":10.0,"direction":"sent","line":{"execute":"guest-fsfreeze-status","arguments":{}}}"#, "\n",
+            r#"{"elapsed":10.0,"direction":"received","line":{"return":"thawed"}}"#, "\n",
+            r#"{"elapsed":10.1,"direction":"sent","line":{"execute":"guest-fsfreeze-thaw","arguments":{}}}"#, "\n",
+            r#"{"elapsed":10.1,"direction":"received","line":{"return":0}}"#, "\n",
+        ).as_bytes()).unwrap();
+        let mut qga = Qga::from_stream(replay.clone());
+        let mut guard = qga.fsfreeze(None).unwrap();
+        assert_eq!(guard.frozen_status().unwrap(), 2);
+        assert_eq!(guard.frozen_status().unwrap(), 0);
+        assert_eq!(guard.thaw().unwrap(), 0);
+        replay.finish().unwrap();
+    }
+}
@@ -0,0 +1,432 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::sync::{Arc, Weak, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::pin::Pin;
+#[cfg(feature = "async-tokio-time")]
+use std::time::Duration;
+use std::{io, fmt, error};
+use futures::{Future, Sink, Stream, StreamExt};
+use futures::future::{self, Either};
+use qapi_qmp::Event;
+use crate::{Command, Execute, ExecuteError, Timestamp};
+use super::{QapiService, QmpEventSubscription};
+
+/// What happens when a subscriber's buffer is full and another event arrives
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum QmpLagPolicy {
+    /// Discard the oldest buffered event to make room
+    #[default]
+    DropOldest,
+    /// Discard the incoming event
+    DropNewest,
+    /// Stop delivering events to the subscriber
+    Disconnect,
+}
+
+#[derive(Debug)]
+pub enum QmpBroadcastError {
+    /// The subscriber fell behind, and this many events were discarded
+    Lagged(u64),
+    /// The underlying event stream failed
+    Io(io::Error),
+}
+
+impl fmt::Display for QmpBroadcastError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QmpBroadcastError::Lagged(count) => write!(f, "QMP event subscriber lagged behind by {} events", count),
+            QmpBroadcastError::Io(e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+impl error::Error for QmpBroadcastError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            QmpBroadcastError::Lagged(..) => None,
+            QmpBroadcastError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<QmpBroadcastError> for io::Error {
+    fn from(e: QmpBroadcastError) -> Self {
+        match e {
+            QmpBroadcastError::Io(e) => e,
+            e @ QmpBroadcastError::Lagged(..) => io::Error::other(e),
+        }
+    }
+}
+
+type EventFilter = Box<dyn FnMut(&Event) -> bool + Send>;
+
+struct SubscriberState {
+    // rejected events are never buffered, so they cannot crowd out those that are wanted
+    filter: Option<EventFilter>,
+    queue: VecDeque<Event>,
+    capacity: usize,
+    policy: QmpLagPolicy,
+    lagged: u64,
+    disconnected: bool,
+    closed: bool,
+    error: Option<io::Error>,
+    waker: Option<Waker>,
+}
+
+impl SubscriberState {
+    fn new(capacity: usize, policy: QmpLagPolicy) -> Self {
+        assert!(capacity > 0, "QMP event subscriber capacity must be non-zero");
+
+        Self {
+            filter: None,
+            queue: VecDeque::with_capacity(capacity),
+            capacity,
+            policy,
+            lagged: 0,
+            disconnected: false,
+            closed: false,
+            error: None,
+            waker: None,
+        }
+    }
+
+    fn push(&mut self, event: &Event) {
+        if self.disconnected || self.filter.as_mut().is_some_and(|filter| !filter(event)) {
+            return
+        }
+
+        if self.queue.len() >= self.capacity {
+            self.lagged += 1;
+            match self.policy {
+                QmpLagPolicy::DropOldest => {
+                    self.queue.pop_front();
+                },
+                QmpLagPolicy::DropNewest => return,
+                QmpLagPolicy::Disconnect => {
+                    self.disconnected = true;
+                    self.queue.clear();
+                    self.wake();
+                    return
+                },
+            }
+        }
+
+        self.queue.push_back(event.clone());
+        self.wake();
+    }
+
+    fn close(&mut self, error: Option<&io::Error>) {
+        self.closed = true;
+        self.error = error.map(|e| io::Error::new(e.kind(), e.to_string()));
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake()
+        }
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    subscribers: Vec<Weak<Mutex<SubscriberState>>>,
+    closed: bool,
+}
+
+impl Registry {
+    fn for_each<F: FnMut(&mut SubscriberState)>(&mut self, mut f: F) {
+        self.subscribers.retain(|sub| match sub.upgrade() {
+            Some(sub) => {
+                f(&mut sub.lock().unwrap());
+                true
+            },
+            None => false,
+        })
+    }
+}
+
+/// Delivers events to every [`QmpEventReceiver`] registered with it
+#[derive(Clone, Default)]
+pub(crate) struct QmpEventPublisher {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl QmpEventPublisher {
+    pub(crate) fn subscribe(&self, capacity: usize, policy: QmpLagPolicy) -> QmpEventReceiver {
+        QmpEventReceiver::register(self.registry.clone(), SubscriberState::new(capacity, policy))
+    }
+
+    pub(crate) fn publish(&self, event: Event) {
+        let mut registry = self.registry.lock().unwrap();
+        registry.for_each(|sub| sub.push(&event));
+    }
+
+    pub(crate) fn close(&self, error: Option<&io::Error>) {
+        let mut registry = self.registry.lock().unwrap();
+        registry.closed = true;
+        registry.for_each(|sub| sub.close(error));
+    }
+}
+
+/// Fans out a single QMP event stream to any number of [`QmpEventReceiver`]s
+///
+/// This future must be polled to completion (usually by spawning it) in order
+/// to keep routing command responses; it never waits on slow subscribers.
+#[must_use = "futures do nothing unless polled"]
+pub struct QmpEventBroadcast<S> {
+    events: S,
+    publisher: QmpEventPublisher,
+}
+
+impl<S> QmpEventBroadcast<S> {
+    /// Creates the broadcast along with an initial subscriber
+    pub fn new(events: S, capacity: usize, policy: QmpLagPolicy) -> (Self, QmpEventReceiver) {
+        let publisher = QmpEventPublisher::default();
+        let receiver = publisher.subscribe(capacity, policy);
+
+        (Self {
+            events,
+            publisher,
+        }, receiver)
+    }
+}
+
+impl<S: Stream<Item=io::Result<Event>>> Future for QmpEventBroadcast<S> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut events = unsafe { Pin::new_unchecked(&mut this.events) };
+
+        loop {
+            match futures::ready!(events.as_mut().poll_next(cx)) {
+                Some(Ok(event)) => this.publisher.publish(event),
+                Some(Err(e)) => {
+                    this.publisher.close(Some(&e));
+                    return Poll::Ready(Err(e))
+                },
+                None => {
+                    this.publisher.close(None);
+                    return Poll::Ready(Ok(()))
+                },
+            }
+        }
+    }
+}
+
+/// A subscriber handle for a [`QmpEventBroadcast`], with its own bounded buffer
+///
+/// Cloning a receiver creates a new subscriber with the same configuration,
+/// which only sees events broadcast after it was created.
+pub struct QmpEventReceiver {
+    registry: Arc<Mutex<Registry>>,
+    state: Arc<Mutex<SubscriberState>>,
+}
+
+impl QmpEventReceiver {
+    fn register(registry: Arc<Mutex<Registry>>, mut state: SubscriberState) -> Self {
+        let state = {
+            let mut registry = registry.lock().unwrap();
+            state.closed = registry.closed;
+            let state = Arc::new(Mutex::new(state));
+            registry.subscribers.push(Arc::downgrade(&state));
+            state
+        };
+
+        Self {
+            registry,
+            state,
+        }
+    }
+
+    /// Creates a new subscriber with a different buffer configuration
+    pub fn resubscribe_with(&self, capacity: usize, policy: QmpLagPolicy) -> Self {
+        Self::register(self.registry.clone(), SubscriberState::new(capacity, policy))
+    }
+
+    /// Creates a subscriber that only ever buffers the first event of type `E` matching `predicate`
+    fn first_event<E, F>(&self, mut predicate: F) -> QmpEventSubscription<Self, E> where
+        E: crate::Event + TryFrom<Event, Error=Event> + 'static,
+        F: FnMut(&E) -> bool + Send + 'static,
+    {
+        let state = SubscriberState {
+            filter: Some(Box::new(move |event: &Event| event.name() == E::NAME
+                && E::try_from(event.clone()).is_ok_and(|data| predicate(&data))
+            )),
+            .. SubscriberState::new(1, QmpLagPolicy::DropNewest)
+        };
+        Self::register(self.registry.clone(), state).subscribe()
+    }
+
+    /// Yields only events of type `E`, along with their timestamps
+    pub fn subscribe<E>(self) -> QmpEventSubscription<Self, E> {
+        QmpEventSubscription::new(self)
+    }
+
+    /// Yields only events of type `E` that match `predicate`
+    pub fn subscribe_with<E, F: FnMut(&E) -> bool>(self, predicate: F) -> QmpEventSubscription<Self, E, F> {
+        QmpEventSubscription::with_predicate(self, predicate)
+    }
+}
+
+impl Clone for QmpEventReceiver {
+    fn clone(&self) -> Self {
+        let (capacity, policy) = {
+            let state = self.state.lock().unwrap();
+            (state.capacity, state.policy)
+        };
+        self.resubscribe_with(capacity, policy)
+    }
+}
+
+impl Stream for QmpEventReceiver {
+    type Item = Result<Event, QmpBroadcastError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.lagged > 0 {
+            let lagged = state.lagged;
+            state.lagged = 0;
+            return Poll::Ready(Some(Err(QmpBroadcastError::Lagged(lagged))))
+        }
+
+        if let Some(event) = state.queue.pop_front() {
+            return Poll::Ready(Some(Ok(event)))
+        }
+
+        if let Some(e) = state.error.take() {
+            return Poll::Ready(Some(Err(QmpBroadcastError::Io(e))))
+        }
+
+        if state.closed || state.disconnected {
+            return Poll::Ready(None)
+        }
+
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Waits for the event that a [`QmpEventReceiver::first_event`] subscription retains
+async fn next_event<E>(subscription: &mut QmpEventSubscription<QmpEventReceiver, E>) -> Result<(E, Timestamp), ExecuteError> where
+    E: crate::Event + TryFrom<Event, Error=Event>,
+{
+    loop {
+        match subscription.next().await {
+            Some(Ok(event)) => return Ok(event),
+            // only later matches were discarded
+            Some(Err(QmpBroadcastError::Lagged(..))) => (),
+            Some(Err(QmpBroadcastError::Io(e))) => return Err(e.into()),
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                format!("QMP event stream closed while waiting for {}", E::NAME)
+            ).into()),
+        }
+    }
+}
+
+impl<W> QapiService<W> {
+    /// Executes a command, then waits for a matching event that reports its completion
+    ///
+    /// A private subscription is registered before the command is sent, so an event that
+    /// arrives ahead of the command's response is not missed. It only retains the first
+    /// matching event, so it is unaffected by `events`' own capacity and lag policy. Fails with
+    /// `TimedOut` once `deadline` resolves.
+    pub fn execute_with_event_until<C, E, F, D>(&self, command: C, events: &QmpEventReceiver, predicate: F, deadline: D) -> impl Future<Output=Result<(C::Ok, E, Timestamp), ExecuteError>> where
+        C: Command,
+        W: Sink<Execute<C, u32>, Error=io::Error> + Unpin,
+        E: crate::Event + TryFrom<Event, Error=Event> + 'static,
+        F: FnMut(&E) -> bool + Send + 'static,
+        D: Future<Output=()>,
+    {
+        let mut subscription = events.first_event::<E, F>(predicate);
+        let execute = self.execute_(command, None::<future::Pending<()>>);
+
+        let wait = async move {
+            let res = execute.await?;
+            let (event, timestamp) = next_event(&mut subscription).await?;
+            Ok((res, event, timestamp))
+        };
+
+        async move {
+            futures::pin_mut!(wait);
+            futures::pin_mut!(deadline);
+            match future::select(wait, deadline).await {
+                Either::Left((res, _)) => res,
+                Either::Right(((), _)) => Err(io::Error::new(io::ErrorKind::TimedOut,
+                    format!("QAPI command {} timed out waiting for {}", C::NAME, E::NAME)
+                ).into()),
+            }
+        }
+    }
+
+    #[cfg(feature = "async-tokio-time")]
+    pub fn execute_with_event_timeout<C, E, F>(&self, command: C, events: &QmpEventReceiver, predicate: F, timeout: Duration) -> impl Future<Output=Result<(C::Ok, E, Timestamp), ExecuteError>> where
+        C: Command,
+        W: Sink<Execute<C, u32>, Error=io::Error> + Unpin,
+        E: crate::Event + TryFrom<Event, Error=Event> + 'static,
+        F: FnMut(&E) -> bool + Send + 'static,
+    {
+        self.execute_with_event_until(command, events, predicate, ::tokio::time::sleep(timeout))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{StreamExt, executor::block_on, stream};
+    use qapi_qmp::{Event, STOP, JOB_STATUS_CHANGE};
+    use super::*;
+
+    fn stop() -> Event {
+        Event::STOP {
+            data: STOP { },
+            timestamp: serde_json::from_str(r#"{"seconds": 0, "microseconds": 0}"#).unwrap(),
+        }
+    }
+
+    #[test]
+    fn first_event() {
+        let job = |id: &str| -> Event {
+            serde_json::from_value(serde_json::json!({
+                "event": "JOB_STATUS_CHANGE",
+                "data": { "id": id, "status": "concluded" },
+                "timestamp": { "seconds": 0, "microseconds": 0 },
+            })).unwrap()
+        };
+        let events = (0..8).map(|_| stop())
+            .chain((0..4).map(|_| job("other")))
+            .chain((0..2).map(|_| job("job0")));
+        let (broadcast, receiver) = QmpEventBroadcast::new(stream::iter(events.map(Ok)), 2, QmpLagPolicy::Disconnect);
+        let mut subscription = receiver.first_event::<JOB_STATUS_CHANGE, _>(|e| e.id == "job0");
+
+        block_on(broadcast).unwrap();
+
+        let (event, _) = block_on(next_event(&mut subscription)).unwrap();
+        assert_eq!(event.id, "job0");
+        assert!(block_on(next_event(&mut subscription)).is_err());
+    }
+
+    #[test]
+    fn lagging_subscriber() {
+        let events = stream::iter((0..4).map(|_| Ok(stop())));
+        let (broadcast, fast) = QmpEventBroadcast::new(events, 4, QmpLagPolicy::DropOldest);
+        let slow = fast.resubscribe_with(2, QmpLagPolicy::DropOldest);
+        let disconnected = fast.resubscribe_with(1, QmpLagPolicy::Disconnect);
+
+        block_on(broadcast).unwrap();
+
+        let fast: Vec<_> = block_on(fast.collect());
+        assert_eq!(fast.len(), 4);
+        assert!(fast.iter().all(|e| e.is_ok()));
+
+        let slow: Vec<_> = block_on(slow.collect());
+        assert_eq!(slow.len(), 3);
+        assert!(matches!(slow[0], Err(QmpBroadcastError::Lagged(2))));
+
+        let disconnected: Vec<_> = block_on(disconnected.collect());
+        assert_eq!(disconnected.len(), 1);
+        assert!(matches!(disconnected[0], Err(QmpBroadcastError::Lagged(1))));
+    }
+}
@@ -1,47 +1,197 @@
-use std::io;
+use std::{fmt, io};
 use std::marker::PhantomData;
-use bytes::{BytesMut, BufMut};
+use bytes::{BytesMut, Buf, BufMut};
 use serde::{de::DeserializeOwned, Serialize};
+use log::warn;
 
+/// Marks the start of a response to `guest-sync-delimited`, and is never valid JSON
+pub const SENTINEL: u8 = 0xff;
+
+/// How much of an undecodable line is quoted in its error
+const ERROR_CONTEXT: usize = 128;
+
+fn line_error<E: fmt::Display>(kind: io::ErrorKind, e: E, line: &[u8]) -> io::Error {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let quoted = String::from_utf8_lossy(&line[..line.len().min(ERROR_CONTEXT)]);
+    let truncated = if line.len() > ERROR_CONTEXT { "..." } else { "" };
+    io::Error::new(kind, format!("{} in QAPI message {:?}{}", e, quoted, truncated))
+}
+
+fn line_too_long(max: usize, line: &[u8]) -> io::Error {
+    line_error(io::ErrorKind::InvalidData, format_args!("line exceeds {} bytes", max), line)
+}
+
+/// Decodes newline-delimited QAPI messages of type `D`, and encodes messages as single lines
+///
+/// An undecodable line fails the stream unless [`set_skip_invalid`](Self::set_skip_invalid) or
+/// [`set_resync_on_error`](Self::set_resync_on_error) is enabled. Either way the line is lost, so a
+/// command whose response it carried is left waiting; pair those modes with a timeout.
 pub struct JsonLinesCodec<D = ()> {
     next_index: usize,
+    discard: bool,
+    // the rest of an overlong line is discarded up to the next newline
+    skip_line: bool,
+    resync_on_error: bool,
+    skip_invalid: bool,
+    max_line_length: Option<usize>,
+    error: Option<io::Error>,
     _decoder: PhantomData<fn() -> D>,
 }
 
+impl<D> Default for JsonLinesCodec<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<D> JsonLinesCodec<D> {
     pub fn new() -> Self {
         Self {
             next_index: 0,
+            discard: false,
+            skip_line: false,
+            resync_on_error: false,
+            skip_invalid: false,
+            max_line_length: None,
+            error: None,
             _decoder: PhantomData,
         }
     }
+
+    /// Treats any line longer than `max` bytes as undecodable, rather than buffering it without limit
+    pub fn set_max_line_length(&mut self, max: Option<usize>) {
+        self.max_line_length = max;
+    }
+
+    /// Logs and skips undecodable lines rather than failing the stream
+    ///
+    /// [`set_resync_on_error`](Self::set_resync_on_error) takes precedence when both are enabled.
+    pub fn set_skip_invalid(&mut self, enable: bool) {
+        self.skip_invalid = enable;
+    }
+
+    /// Discards all input up to and including the next `0xff` sentinel byte
+    pub fn resync(&mut self) {
+        self.discard = true;
+        self.next_index = 0;
+    }
+
+    /// Rather than failing the stream, an undecodable line is held for
+    /// [`take_error`](Self::take_error) and input is discarded until the next sentinel.
+    pub fn set_resync_on_error(&mut self, enable: bool) {
+        self.resync_on_error = enable;
+    }
+
+    pub fn has_error(&self) -> bool {
+        self.error.is_some()
+    }
+
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    fn invalid(&mut self, e: io::Error) -> io::Result<()> {
+        if self.resync_on_error {
+            self.error.get_or_insert(e);
+            self.discard = true;
+            self.skip_line = false;
+            Ok(())
+        } else if self.skip_invalid {
+            warn!("Skipping undecodable QAPI message: {}", e);
+            Ok(())
+        } else {
+            Err(e)
+        }
+    }
 }
 
 impl<D: DeserializeOwned> JsonLinesCodec<D> {
-    fn priv_decode(&mut self, buf: &mut BytesMut) -> Result<Option<D>, io::Error> {
-        match memchr::memchr(b'\n', &buf[self.next_index..]) {
-            Some(offset) => {
-                let index = offset + self.next_index;
-                self.next_index = 0;
-                let line = buf.split_to(index + 1);
-                serde_json::from_slice(&line)
-                    .map_err(From::from)
-                    .map(Some)
-            },
-            None => {
-                self.next_index = buf.len();
-                Ok(None)
-            },
+    pub(super) fn priv_decode(&mut self, buf: &mut BytesMut) -> Result<Option<D>, io::Error> {
+        loop {
+            if self.discard {
+                match memchr::memchr(SENTINEL, buf) {
+                    Some(offset) => {
+                        buf.advance(offset + 1);
+                        self.discard = false;
+                    },
+                    None => {
+                        buf.clear();
+                        return Ok(None)
+                    },
+                }
+            }
+
+            if self.skip_line {
+                match memchr::memchr(b'\n', buf) {
+                    Some(offset) => {
+                        buf.advance(offset + 1);
+                        self.skip_line = false;
+                    },
+                    None => {
+                        buf.clear();
+                        return Ok(None)
+                    },
+                }
+            }
+
+            match memchr::memchr(b'\n', &buf[self.next_index..]) {
+                Some(offset) => {
+                    let index = offset + self.next_index;
+                    self.next_index = 0;
+                    let line = buf.split_to(index + 1);
+                    // the limit excludes the terminating newline
+                    let res = match self.max_line_length {
+                        Some(max) if index > max => Err(line_too_long(max, &line)),
+                        _ => serde_json::from_slice(&line)
+                            .map_err(|e| line_error(io::ErrorKind::InvalidData, e, &line)),
+                    };
+                    match res {
+                        Ok(res) => return Ok(Some(res)),
+                        Err(e) => self.invalid(e)?,
+                    }
+                },
+                None => match self.max_line_length {
+                    Some(max) if buf.len() > max => {
+                        let e = line_too_long(max, buf);
+                        buf.clear();
+                        self.next_index = 0;
+                        self.skip_line = true;
+                        self.invalid(e)?;
+                    },
+                    _ => {
+                        self.next_index = buf.len();
+                        return Ok(None)
+                    },
+                },
+            }
         }
     }
 
-    fn priv_decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<D>, io::Error> {
-        if buf.is_empty() {
-            Ok(None)
-        } else {
-            serde_json::from_slice(buf)
-                .map_err(From::from)
-                .map(Some)
+    pub(super) fn priv_decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<D>, io::Error> {
+        // the final line is unterminated, and is consumed whether or not it decodes
+        let line = buf.split();
+        if self.discard || self.skip_line || line.is_empty() {
+            return Ok(None)
+        }
+
+        let res = match self.max_line_length {
+            Some(max) if line.len() > max => Err(line_too_long(max, &line)),
+            _ => serde_json::from_slice(&line).map_err(|e| {
+                let kind = match e.classify() {
+                    serde_json::error::Category::Eof => io::ErrorKind::UnexpectedEof,
+                    _ => io::ErrorKind::InvalidData,
+                };
+                line_error(kind, e, &line)
+            }),
+        };
+        match res {
+            Ok(res) => Ok(Some(res)),
+            // there is nothing left to resynchronise with
+            Err(e) if self.skip_invalid => {
+                warn!("Skipping undecodable QAPI message: {}", e);
+                Ok(None)
+            },
+            Err(e) => Err(e),
         }
     }
 }
@@ -60,7 +210,7 @@ impl<D: DeserializeOwned> tokio_util::codec::Decoder for JsonLinesCodec<D> {
     }
 }
 
-fn encode<S: Serialize>(item: S, bytes: &mut BytesMut) -> Result<(), io::Error> {
+pub(super) fn encode<S: Serialize>(item: S, bytes: &mut BytesMut) -> Result<(), io::Error> {
     serde_json::to_writer(bytes.writer(), &item)?;
     bytes.put_u8(b'\n');
     Ok(())
@@ -74,3 +224,72 @@ impl<T, S: Serialize> tokio_util::codec::Encoder<S> for JsonLinesCodec<T> {
         encode(item, bytes)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+    use super::*;
+
+    #[test]
+    fn resync_on_error() {
+        let mut codec = JsonLinesCodec::<u32>::new();
+        codec.set_resync_on_error(true);
+
+        let mut buf = BytesMut::from(&b"1\n{\"stale\n2\n\xff3\n4"[..]);
+        assert_eq!(codec.priv_decode(&mut buf).unwrap(), Some(1));
+        assert_eq!(codec.priv_decode(&mut buf).unwrap(), Some(3));
+        assert!(codec.take_error().is_some());
+        assert_eq!(codec.priv_decode(&mut buf).unwrap(), None);
+        assert_eq!(&buf[..], b"4");
+    }
+
+    #[test]
+    fn line_limits() {
+        let mut codec = JsonLinesCodec::<u32>::new();
+        codec.set_max_line_length(Some(4));
+
+        let mut buf = BytesMut::from(&b"1\n1234\n123456"[..]);
+        assert_eq!(codec.priv_decode(&mut buf).unwrap(), Some(1));
+        assert_eq!(codec.priv_decode(&mut buf).unwrap(), Some(1234));
+        let e = codec.priv_decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("\"123456\""));
+
+        codec.set_skip_invalid(true);
+        buf.extend_from_slice(b"789\n[\n2\n");
+        assert_eq!(codec.priv_decode(&mut buf).unwrap(), Some(2));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn eof_line_limits() {
+        let mut codec = JsonLinesCodec::<u32>::new();
+        codec.set_max_line_length(Some(4));
+
+        let mut buf = BytesMut::from(&b"1234"[..]);
+        assert_eq!(codec.priv_decode(&mut buf).unwrap(), None);
+        assert_eq!(codec.priv_decode_eof(&mut buf).unwrap(), Some(1234));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"123456"[..]);
+        let e = codec.priv_decode_eof(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("\"123456\""));
+
+        codec.set_skip_invalid(true);
+        for line in [&b"123456"[..], b"["] {
+            let mut buf = BytesMut::from(line);
+            assert_eq!(codec.priv_decode_eof(&mut buf).unwrap(), None);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn truncated_error() {
+        let mut codec = JsonLinesCodec::<u32>::new();
+        let mut buf = BytesMut::from(&[b'x'; 1000][..]);
+        buf.put_u8(b'\n');
+        let e = codec.priv_decode(&mut buf).unwrap_err().to_string();
+        assert!(e.contains(&format!("\"{}\"...", "x".repeat(ERROR_CONTEXT))));
+    }
+}
@@ -0,0 +1,263 @@
+use std::io;
+use std::time::{Duration, Instant};
+use futures::{Future, Sink};
+use futures::future::{self, Either};
+use log::warn;
+use qapi_qga::{guest_fsfreeze_freeze, guest_fsfreeze_freeze_list, guest_fsfreeze_thaw, guest_fsfreeze_status, GuestFsfreezeStatus};
+use crate::fsfreeze::{fsfreeze_timeout, fsfreeze_remaining};
+use crate::{Execute, ExecuteError};
+use super::QapiService;
+
+/// The filesystem freeze commands that [`QapiService::fsfreeze_scope`] sends
+pub trait GuestFsfreezeSink:
+    Sink<Execute<guest_fsfreeze_freeze, u32>, Error=io::Error> +
+    Sink<Execute<guest_fsfreeze_freeze_list, u32>, Error=io::Error> +
+    Sink<Execute<guest_fsfreeze_thaw, u32>, Error=io::Error> +
+    Sink<Execute<guest_fsfreeze_status, u32>, Error=io::Error> +
+    Unpin + Send + 'static
+{ }
+
+impl<W> GuestFsfreezeSink for W where W:
+    Sink<Execute<guest_fsfreeze_freeze, u32>, Error=io::Error> +
+    Sink<Execute<guest_fsfreeze_freeze_list, u32>, Error=io::Error> +
+    Sink<Execute<guest_fsfreeze_thaw, u32>, Error=io::Error> +
+    Sink<Execute<guest_fsfreeze_status, u32>, Error=io::Error> +
+    Unpin + Send + 'static
+{ }
+
+/// The guest's frozen filesystems, as seen from within a [`QapiService::fsfreeze_scope`]
+pub struct GuestFsfreeze<'a, W> {
+    service: &'a QapiService<W>,
+    frozen: i64,
+    started: Instant,
+}
+
+impl<'a, W> Clone for GuestFsfreeze<'a, W> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service,
+            frozen: self.frozen,
+            started: self.started,
+        }
+    }
+}
+
+impl<'a, W: GuestFsfreezeSink> GuestFsfreeze<'a, W> {
+    /// The number of filesystems that were frozen
+    pub fn frozen(&self) -> i64 {
+        self.frozen
+    }
+
+    /// How long the filesystems have been frozen for
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The agent, which may still be used while the guest is frozen
+    pub fn service(&self) -> &'a QapiService<W> {
+        self.service
+    }
+
+    pub async fn status(&self) -> Result<GuestFsfreezeStatus, ExecuteError> {
+        self.service.execute(guest_fsfreeze_status { }).await
+    }
+
+    /// Queries `guest-fsfreeze-status` for the number of filesystems that are still frozen
+    ///
+    /// See [`QgaFreezeGuard::frozen_status`](crate::QgaFreezeGuard::frozen_status).
+    pub async fn frozen_status(&self) -> Result<i64, ExecuteError> {
+        self.status().await.map(|status| fsfreeze_remaining(status, self.frozen))
+    }
+}
+
+/// Thaws the guest if a scope is cancelled before it can do so itself
+struct ThawOnDrop<'a, W: GuestFsfreezeSink> {
+    service: Option<&'a QapiService<W>>,
+}
+
+impl<'a, W: GuestFsfreezeSink> ThawOnDrop<'a, W> {
+    fn disarm(mut self) {
+        self.service = None;
+    }
+}
+
+impl<'a, W: GuestFsfreezeSink> Drop for ThawOnDrop<'a, W> {
+    fn drop(&mut self) {
+        let service = match self.service.take() {
+            Some(service) => service,
+            None => return,
+        };
+
+        let thaw = service.execute(guest_fsfreeze_thaw { });
+        #[cfg(feature = "async-tokio-spawn")]
+        if let Ok(runtime) = ::tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if let Err(e) = thaw.await {
+                    warn!("failed to thaw guest filesystems: {}", e);
+                }
+            });
+            return
+        }
+
+        drop(thaw);
+        warn!("filesystem freeze was cancelled, and the guest remains frozen");
+    }
+}
+
+impl<W: GuestFsfreezeSink> QapiService<W> {
+    /// Runs `scope` while the guest's filesystems, or only those at `mountpoints`, are frozen
+    ///
+    /// `scope` is given a [`GuestFsfreeze`] describing the frozen filesystems, and the guest is
+    /// thawed once it completes, whether or not it succeeded. If this future is dropped before the thaw has
+    /// completed, another thaw is spawned in the background when the `async-tokio-spawn` feature is
+    /// enabled and a Tokio runtime is running. Otherwise no thaw is ever sent: a warning is logged,
+    /// and the guest remains frozen until it is thawed explicitly.
+    pub async fn fsfreeze_scope<'a, T, E, F, S>(&'a self, mountpoints: Option<Vec<String>>, scope: F) -> Result<T, E> where
+        F: FnOnce(GuestFsfreeze<'a, W>) -> S,
+        S: Future<Output=Result<T, E>>,
+        E: From<ExecuteError>,
+    {
+        self.fsfreeze_scope_(mountpoints, None::<future::Pending<()>>, scope).await
+    }
+
+    /// Like [`fsfreeze_scope`](Self::fsfreeze_scope), but abandons `scope` and thaws once
+    /// `deadline` resolves, failing with `TimedOut`
+    pub async fn fsfreeze_scope_until<'a, T, E, F, S, D>(&'a self, mountpoints: Option<Vec<String>>, deadline: D, scope: F) -> Result<T, E> where
+        F: FnOnce(GuestFsfreeze<'a, W>) -> S,
+        S: Future<Output=Result<T, E>>,
+        E: From<ExecuteError>,
+        D: Future<Output=()>,
+    {
+        self.fsfreeze_scope_(mountpoints, Some(deadline), scope).await
+    }
+
+    #[cfg(feature = "async-tokio-time")]
+    pub async fn fsfreeze_scope_timeout<'a, T, E, F, S>(&'a self, mountpoints: Option<Vec<String>>, timeout: Duration, scope: F) -> Result<T, E> where
+        F: FnOnce(GuestFsfreeze<'a, W>) -> S,
+        S: Future<Output=Result<T, E>>,
+        E: From<ExecuteError>,
+    {
+        self.fsfreeze_scope_until(mountpoints, ::tokio::time::sleep(timeout), scope).await
+    }
+
+    async fn fsfreeze_scope_<'a, T, E, F, S, D>(&'a self, mountpoints: Option<Vec<String>>, deadline: Option<D>, scope: F) -> Result<T, E> where
+        F: FnOnce(GuestFsfreeze<'a, W>) -> S,
+        S: Future<Output=Result<T, E>>,
+        E: From<ExecuteError>,
+        D: Future<Output=()>,
+    {
+        let frozen = match mountpoints {
+            None => self.execute(guest_fsfreeze_freeze { }).await?,
+            Some(mountpoints) => self.execute(guest_fsfreeze_freeze_list {
+                mountpoints: Some(mountpoints),
+            }).await?,
+        };
+        let guard = ThawOnDrop {
+            service: Some(self),
+        };
+
+        let started = Instant::now();
+        let scope = scope(GuestFsfreeze {
+            service: self,
+            frozen,
+            started,
+        });
+        let res = match deadline {
+            None => scope.await,
+            Some(deadline) => {
+                futures::pin_mut!(scope);
+                futures::pin_mut!(deadline);
+                match future::select(scope, deadline).await {
+                    Either::Left((res, _)) => res,
+                    Either::Right(((), _)) => Err(fsfreeze_timeout(started.elapsed()).into()),
+                }
+            },
+        };
+
+        // the guard remains armed in case this is dropped while thawing
+        let thawed = self.execute(guest_fsfreeze_thaw { }).await;
+        guard.disarm();
+        match (res, thawed) {
+            (Ok(res), Ok(..)) => Ok(res),
+            (Ok(..), Err(e)) => Err(e.into()),
+            (Err(e), thawed) => {
+                if let Err(thaw) = thawed {
+                    warn!("failed to thaw guest filesystems: {}", thaw);
+                }
+                Err(e)
+            },
+        }
+    }
+}
+
+#[cfg(all(test, feature = "async-futures-io"))]
+mod test {
+    use std::io;
+    use futures::future;
+    use crate::futures::QgaStreamFutures;
+    use crate::fsfreeze::test::TRANSCRIPT;
+    use crate::{QapiReplay, ExecuteError};
+
+    fn replay() -> QapiReplay {
+        QapiReplay::from_reader(TRANSCRIPT.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn thaw_on_error() {
+        let replay = replay();
+        let (service, events) = QgaStreamFutures::open_split(replay.clone(), replay.clone()).into_parts();
+        let test = async {
+            let res = service.fsfreeze_scope(None, |fsfreeze| async move {
+                assert_eq!(fsfreeze.frozen(), 2);
+                Err::<(), ExecuteError>(io::Error::other("snapshot failed").into())
+            }).await;
+            assert_eq!(res.unwrap_err().to_string(), "snapshot failed");
+        };
+        let (res, ()) = futures::executor::block_on(async { futures::join!(events, test) });
+        res.unwrap();
+        replay.finish().unwrap();
+    }
+
+    #[test]
+    fn thaw_on_timeout() {
+        let replay = replay();
+        let (service, events) = QgaStreamFutures::open_split(replay.clone(), replay.clone()).into_parts();
+        let test = async {
+            let res = service.fsfreeze_scope_until(None, future::ready(()), |_| future::pending::<Result<(), ExecuteError>>()).await;
+            assert_eq!(io::Error::from(res.unwrap_err()).kind(), io::ErrorKind::TimedOut);
+        };
+        let (res, ()) = futures::executor::block_on(async { futures::join!(events, test) });
+        res.unwrap();
+        replay.finish().unwrap();
+    }
+
+    #[cfg(feature = "async-tokio-spawn")]
+    #[test]
+    fn thaw_on_drop() {
+        use futures::channel::oneshot;
+
+        let replay = replay();
+        let runtime = ::tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let (service, events) = QgaStreamFutures::open_split(replay.clone(), replay.clone()).into_parts();
+            let events = ::tokio::spawn(events);
+
+            let (enter, entered) = oneshot::channel();
+            let scope = Box::pin(service.fsfreeze_scope(None, |_| async move {
+                let _ = enter.send(());
+                future::pending::<Result<(), ExecuteError>>().await
+            }));
+            match future::select(scope, entered).await {
+                future::Either::Right((res, scope)) => {
+                    res.unwrap();
+                    drop(scope);
+                },
+                future::Either::Left(..) => unreachable!(),
+            }
+
+            // the thaw is spawned, and the stream ends once its response has been read
+            events.await.unwrap().unwrap();
+        });
+        replay.finish().unwrap();
+    }
+}
@@ -0,0 +1,414 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::sync::Arc;
+#[cfg(feature = "qapi-qga")]
+use std::sync::atomic::Ordering;
+use bytes::{BytesMut, Buf};
+use futures::{Stream, Sink};
+use futures::io::{AsyncRead, AsyncWrite, AsyncReadExt, ReadHalf, WriteHalf};
+use serde::{Serialize, de::DeserializeOwned};
+use qapi_spec::{Response, Any};
+#[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+use qapi_spec::{Execute, ExecuteRaw};
+#[cfg(feature = "qapi-qmp")]
+use qapi_spec::ExecuteOob;
+#[cfg(feature = "qapi-qmp")]
+use qapi_qmp::{QmpMessageAny, QmpCommand, QapiCapabilities};
+#[cfg(feature = "qapi-qmp")]
+use super::QmpStreamNegotiation;
+use super::codec::{self, JsonLinesCodec};
+use super::resync::{QgaSyncState, QgaFramed, QgaCodec};
+use super::{QapiEvents, QapiService, QapiStream, QapiShared};
+
+const READ_CHUNK: usize = 8 * 1024;
+const BACKPRESSURE_BOUNDARY: usize = 8 * 1024;
+
+/// Newline-delimited JSON framing over a `futures::io` reader and/or writer
+struct JsonLinesFramed<S, D> {
+    io: S,
+    codec: JsonLinesCodec<D>,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    eof: bool,
+}
+
+impl<S, D> JsonLinesFramed<S, D> {
+    fn new(io: S) -> Self {
+        Self {
+            io,
+            codec: JsonLinesCodec::new(),
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            eof: false,
+        }
+    }
+
+    /// Switches to decoding a different message type, keeping any buffered input
+    #[cfg(feature = "qapi-qmp")]
+    fn map_codec<E>(self) -> JsonLinesFramed<S, E> {
+        JsonLinesFramed {
+            io: self.io,
+            codec: JsonLinesCodec::new(),
+            read_buf: self.read_buf,
+            write_buf: self.write_buf,
+            eof: self.eof,
+        }
+    }
+
+    fn io(self: Pin<&mut Self>) -> Pin<&mut S> {
+        unsafe {
+            self.map_unchecked_mut(|this| &mut this.io)
+        }
+    }
+}
+
+impl<S: AsyncRead, D: DeserializeOwned> Stream for JsonLinesFramed<S, D> {
+    type Item = io::Result<D>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut io = unsafe { Pin::new_unchecked(&mut this.io) };
+
+        loop {
+            if let Some(item) = this.codec.priv_decode(&mut this.read_buf)? {
+                return Poll::Ready(Some(Ok(item)))
+            }
+
+            if this.eof {
+                let res = this.codec.priv_decode_eof(&mut this.read_buf);
+                this.read_buf.clear();
+                return Poll::Ready(res.transpose())
+            }
+
+            let len = this.read_buf.len();
+            this.read_buf.resize(len + READ_CHUNK, 0);
+            let res = io.as_mut().poll_read(cx, &mut this.read_buf[len..]);
+            let read = match res {
+                Poll::Ready(Ok(read)) => read,
+                res => {
+                    this.read_buf.truncate(len);
+                    return res.map(|res| res.err().map(Err))
+                },
+            };
+            this.read_buf.truncate(len + read);
+            this.eof = read == 0;
+        }
+    }
+}
+
+impl<S: AsyncWrite, D, T: Serialize> Sink<T> for JsonLinesFramed<S, D> {
+    type Error = io::Error;
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        let this = unsafe { self.get_unchecked_mut() };
+        codec::encode(item, &mut this.write_buf)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        if self.write_buf.len() >= BACKPRESSURE_BOUNDARY {
+            Sink::<T>::poll_flush(self, cx)
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut io = unsafe { Pin::new_unchecked(&mut this.io) };
+
+        while !this.write_buf.is_empty() {
+            match futures::ready!(io.as_mut().poll_write(cx, &this.write_buf))? {
+                0 => return Poll::Ready(Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write QAPI message"))),
+                written => this.write_buf.advance(written),
+            }
+        }
+
+        io.poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        futures::ready!(Sink::<T>::poll_flush(self.as_mut(), cx))?;
+        self.io().poll_close(cx)
+    }
+}
+
+impl<S> QgaFramed for JsonLinesFramed<S, Response<Any>> {
+    fn codec(&self) -> &QgaCodec {
+        &self.codec
+    }
+
+    fn codec_mut(&mut self) -> &mut QgaCodec {
+        &mut self.codec
+    }
+
+    #[cfg(feature = "qapi-qga")]
+    fn write_buffer_mut(&mut self) -> &mut BytesMut {
+        &mut self.write_buf
+    }
+}
+
+/// A QGA transport over any `futures::io` stream, independent of the async runtime
+pub struct QgaStreamFutures<S> {
+    stream: JsonLinesFramed<S, Response<Any>>,
+    sync: Arc<QgaSyncState>,
+    buffered: Option<Response<Any>>,
+}
+
+impl<S> QgaStreamFutures<S> {
+    fn new(stream: S, sync: Arc<QgaSyncState>) -> Self {
+        Self {
+            stream: JsonLinesFramed::new(stream),
+            sync,
+            buffered: None,
+        }
+    }
+
+    pub fn open_split<W>(read: S, write: W) -> QapiStream<Self, QgaStreamFutures<W>> {
+        let sync = Arc::new(QgaSyncState::default());
+        let shared = Arc::new(QapiShared::new(false));
+        let events = QapiEvents::new(Self::new(read, sync.clone()), shared.clone());
+        let service = QapiService::new(QgaStreamFutures::new(write, sync), shared);
+
+        QapiStream {
+            service,
+            events,
+        }
+    }
+}
+
+impl<RW: AsyncRead + AsyncWrite> QgaStreamFutures<ReadHalf<RW>> {
+    pub fn open(stream: RW) -> QapiStream<Self, QgaStreamFutures<WriteHalf<RW>>> {
+        let (r, w) = stream.split();
+        Self::open_split(r, w)
+    }
+}
+
+#[cfg(feature = "qapi-qga")]
+impl<R, W> QapiStream<QgaStreamFutures<R>, QgaStreamFutures<W>> {
+    /// Resynchronises using `guest-sync-delimited` before the first command, and again
+    /// after any response fails to decode
+    pub fn set_auto_resync(&mut self, enable: bool) {
+        self.events.stream.sync.set_auto_resync(enable);
+        self.service.shared.resync.store(enable, Ordering::Relaxed);
+    }
+}
+
+impl<R, W> QapiStream<QgaStreamFutures<R>, QgaStreamFutures<W>> {
+    /// Treats any message longer than `max` bytes as undecodable, rather than buffering it without limit
+    pub fn set_max_line_length(&mut self, max: Option<usize>) {
+        self.events.stream.stream.codec.set_max_line_length(max);
+    }
+
+    /// Logs and skips messages that cannot be decoded, rather than failing the stream
+    ///
+    /// See [`JsonLinesCodec`] for how this affects pending commands. Automatic resynchronisation
+    /// takes precedence when it is enabled.
+    pub fn set_skip_invalid_lines(&mut self, enable: bool) {
+        self.events.stream.stream.codec.set_skip_invalid(enable);
+    }
+}
+
+#[cfg(feature = "qapi-qga")]
+impl<S> QgaStreamFutures<S> {
+    fn stream(self: Pin<&mut Self>) -> Pin<&mut JsonLinesFramed<S, Response<Any>>> {
+        unsafe {
+            self.map_unchecked_mut(|this| &mut this.stream)
+        }
+    }
+}
+
+impl<S: AsyncRead> Stream for QgaStreamFutures<S> {
+    type Item = io::Result<Response<Any>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let stream = unsafe { Pin::new_unchecked(&mut this.stream) };
+        this.sync.poll_next(stream, &mut this.buffered, cx)
+    }
+}
+
+#[cfg(feature = "qapi-qga")]
+impl<S: AsyncWrite, C: qapi_qga::QgaCommand, I: Serialize> Sink<Execute<C, I>> for QgaStreamFutures<S> {
+    type Error = io::Error;
+
+    fn start_send(self: Pin<&mut Self>, item: Execute<C, I>) -> Result<(), Self::Error> {
+        let sync = self.sync.clone();
+        sync.start_send::<_, _, I>(self.stream(), C::NAME, item)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<Execute<C, I>>::poll_ready(self.stream(), cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<Execute<C, I>>::poll_flush(self.stream(), cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<Execute<C, I>>::poll_close(self.stream(), cx)
+    }
+}
+
+#[cfg(feature = "qapi-qga")]
+impl<S: AsyncWrite, I: Serialize> Sink<ExecuteRaw<I>> for QgaStreamFutures<S> {
+    type Error = io::Error;
+
+    fn start_send(self: Pin<&mut Self>, item: ExecuteRaw<I>) -> Result<(), Self::Error> {
+        let sync = self.sync.clone();
+        let name = item.execute.clone();
+        sync.start_send::<_, _, I>(self.stream(), &name, item)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteRaw<I>>::poll_ready(self.stream(), cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteRaw<I>>::poll_flush(self.stream(), cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteRaw<I>>::poll_close(self.stream(), cx)
+    }
+}
+
+/// A QMP transport over any `futures::io` stream, independent of the async runtime
+#[cfg(feature = "qapi-qmp")]
+pub struct QmpStreamFutures<S> {
+    stream: JsonLinesFramed<S, QmpMessageAny>,
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S> QmpStreamFutures<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: JsonLinesFramed::new(stream),
+        }
+    }
+
+    fn stream(self: Pin<&mut Self>) -> Pin<&mut JsonLinesFramed<S, QmpMessageAny>> {
+        unsafe {
+            self.map_unchecked_mut(|this| &mut this.stream)
+        }
+    }
+
+    pub async fn open_split<W>(read: S, write: W) -> io::Result<QmpStreamNegotiation<Self, QmpStreamFutures<W>>> where
+        S: AsyncRead + Unpin,
+    {
+        use futures::StreamExt;
+
+        let mut lines = JsonLinesFramed::<_, QapiCapabilities>::new(read);
+
+        let capabilities = lines.next().await.ok_or_else(||
+            io::Error::new(io::ErrorKind::UnexpectedEof, "QMP greeting expected")
+        )??;
+
+        let shared = Arc::new(QapiShared::new(true));
+        let events = QapiEvents::new(Self { stream: lines.map_codec() }, shared.clone());
+        let service = QapiService::new(QmpStreamFutures::new(write), shared);
+
+        Ok(QmpStreamNegotiation {
+            stream: QapiStream {
+                service,
+                events,
+            },
+            capabilities,
+        })
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<RW: AsyncRead + AsyncWrite + Unpin> QmpStreamFutures<ReadHalf<RW>> {
+    pub async fn open(stream: RW) -> io::Result<QmpStreamNegotiation<Self, QmpStreamFutures<WriteHalf<RW>>>> {
+        let (r, w) = stream.split();
+        Self::open_split(r, w).await
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<R, W> QapiStream<QmpStreamFutures<R>, W> {
+    /// Treats any message longer than `max` bytes as undecodable, rather than buffering it without limit
+    pub fn set_max_line_length(&mut self, max: Option<usize>) {
+        self.events.stream.stream.codec.set_max_line_length(max);
+    }
+
+    /// Logs and skips messages that cannot be decoded, rather than failing the stream
+    ///
+    /// See [`JsonLinesCodec`] for how this affects pending commands.
+    pub fn set_skip_invalid_lines(&mut self, enable: bool) {
+        self.events.stream.stream.codec.set_skip_invalid(enable);
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S: AsyncRead> Stream for QmpStreamFutures<S> {
+    type Item = io::Result<QmpMessageAny>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.stream().poll_next(cx)
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S: AsyncWrite, C: QmpCommand, I: Serialize> Sink<Execute<C, I>> for QmpStreamFutures<S> {
+    type Error = io::Error;
+
+    fn start_send(self: Pin<&mut Self>, item: Execute<C, I>) -> Result<(), Self::Error> {
+        self.stream().start_send(item)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<Execute<C, I>>::poll_ready(self.stream(), cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<Execute<C, I>>::poll_flush(self.stream(), cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<Execute<C, I>>::poll_close(self.stream(), cx)
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S: AsyncWrite, C: QmpCommand, I: Serialize> Sink<ExecuteOob<C, I>> for QmpStreamFutures<S> {
+    type Error = io::Error;
+
+    fn start_send(self: Pin<&mut Self>, item: ExecuteOob<C, I>) -> Result<(), Self::Error> {
+        self.stream().start_send(item)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteOob<C, I>>::poll_ready(self.stream(), cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteOob<C, I>>::poll_flush(self.stream(), cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteOob<C, I>>::poll_close(self.stream(), cx)
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S: AsyncWrite, I: Serialize> Sink<ExecuteRaw<I>> for QmpStreamFutures<S> {
+    type Error = io::Error;
+
+    fn start_send(self: Pin<&mut Self>, item: ExecuteRaw<I>) -> Result<(), Self::Error> {
+        self.stream().start_send(item)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteRaw<I>>::poll_ready(self.stream(), cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteRaw<I>>::poll_flush(self.stream(), cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteRaw<I>>::poll_close(self.stream(), cx)
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::VecDeque;
+use std::io;
+use std::time::Instant;
+use futures::{Sink, Stream};
+use log::warn;
+use qapi_qga::{
+    GuestCommand, GuestExecOutput, GuestExecStatus, guest_exec, guest_exec_status,
+    guest_file_open, guest_file_close, guest_file_read, guest_file_seek,
+};
+use crate::guest_exec::{poll_delay, output_paths, output_open, output_read, output_rewind, output_remove, OUTPUTS};
+use crate::{Execute, ExecuteError};
+use super::QapiService;
+
+/// The commands that [`QapiService::guest_exec_stream`] sends
+pub trait GuestExecSink:
+    Sink<Execute<guest_exec, u32>, Error=io::Error> +
+    Sink<Execute<guest_exec_status, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_open, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_close, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_read, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_seek, u32>, Error=io::Error> +
+    Unpin
+{ }
+
+impl<W> GuestExecSink for W where W:
+    Sink<Execute<guest_exec, u32>, Error=io::Error> +
+    Sink<Execute<guest_exec_status, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_open, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_close, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_read, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_seek, u32>, Error=io::Error> +
+    Unpin
+{ }
+
+struct ExecState {
+    command: GuestCommand,
+    pid: Option<i64>,
+    started: Instant,
+    polls: u32,
+    paths: [String; 2],
+    handles: [Option<i64>; 2],
+    eof: [bool; 2],
+    pending: VecDeque<GuestExecOutput>,
+    done: bool,
+}
+
+impl<W: GuestExecSink> QapiService<W> {
+    /// Starts a guest process, and streams its output as it is written until it exits
+    ///
+    /// The stream ends after [`GuestExecOutput::Exited`], or after the first error. The process is
+    /// run by `/bin/sh` with its output redirected to files under `/tmp`, which are read as it runs,
+    /// so this requires a POSIX guest. The files are removed once the stream ends, but are left
+    /// behind if it is dropped before then. Use [`guest_exec`](Self::guest_exec) for other guests.
+    pub fn guest_exec_stream<'a>(&'a self, command: &GuestCommand) -> impl Stream<Item=Result<GuestExecOutput, ExecuteError>> + 'a {
+        let state = ExecState {
+            command: command.clone(),
+            pid: None,
+            started: Instant::now(),
+            polls: 0,
+            paths: output_paths(),
+            handles: [None, None],
+            eof: [false; 2],
+            pending: VecDeque::new(),
+            done: false,
+        };
+
+        futures::stream::unfold(Some(state), move |state| async move {
+            let mut state = state?;
+            loop {
+                if let Some(output) = state.pending.pop_front() {
+                    return Some((Ok(output), Some(state)))
+                }
+                if state.done {
+                    return None
+                }
+
+                if let Err(e) = self.exec_poll(&mut state).await {
+                    self.exec_cleanup(&mut state).await;
+                    return Some((Err(e), None))
+                }
+            }
+        })
+    }
+
+    async fn exec_poll(&self, state: &mut ExecState) -> Result<(), ExecuteError> {
+        let pid = match state.pid {
+            Some(pid) => pid,
+            None => {
+                for (handle, path) in state.handles.iter_mut().zip(&state.paths) {
+                    *handle = Some(self.execute(output_open(path)).await?);
+                }
+                let exec = self.execute(state.command.to_redirected_command(&state.paths[0], &state.paths[1])).await?;
+                state.pid = Some(exec.pid);
+                state.started = Instant::now();
+                exec.pid
+            },
+        };
+
+        let delay = poll_delay(&state.command, pid, state.polls, state.started.elapsed())?;
+        ::tokio::time::sleep(delay).await;
+        state.polls += 1;
+
+        // the status is checked first, so that all output has been written if it has exited
+        let status = self.execute(guest_exec_status { pid }).await?;
+        for (i, &handle) in state.handles.iter().enumerate() {
+            let handle = match handle {
+                Some(handle) => handle,
+                None => continue,
+            };
+            if state.eof[i] {
+                self.execute(output_rewind(handle)?).await?;
+            }
+            loop {
+                let read = self.execute(output_read(handle)).await?;
+                state.eof[i] = read.eof;
+                let empty = read.buf_b64.is_empty();
+                if !empty {
+                    state.pending.push_back(OUTPUTS[i](read.buf_b64));
+                }
+                if read.eof || empty {
+                    break
+                }
+            }
+        }
+        if status.exited {
+            state.done = true;
+            state.pending.extend(status.into_outputs());
+            self.exec_cleanup(state).await;
+        }
+        Ok(())
+    }
+
+    async fn exec_cleanup(&self, state: &mut ExecState) {
+        if state.handles.iter().all(Option::is_none) {
+            return
+        }
+        for handle in state.handles.iter_mut().filter_map(Option::take) {
+            if let Err(e) = self.execute(guest_file_close { handle }).await {
+                warn!("failed to close guest file handle {}: {}", handle, e);
+            }
+        }
+        if let Err(e) = self.execute(output_remove(&state.paths)).await {
+            warn!("failed to remove guest process output {:?}: {}", state.paths, e);
+        }
+    }
+
+    /// Runs a guest process to completion, returning its exit status and captured output
+    ///
+    /// Use [`GuestExecStatus::result`] to treat a failed process as an error.
+    pub async fn guest_exec(&self, command: &GuestCommand) -> Result<GuestExecStatus, ExecuteError> {
+        let pid = self.execute(command.to_command()).await?.pid;
+        let started = Instant::now();
+        let mut polls = 0;
+        loop {
+            ::tokio::time::sleep(poll_delay(command, pid, polls, started.elapsed())?).await;
+            polls += 1;
+
+            let status = self.execute(guest_exec_status { pid }).await?;
+            if status.exited {
+                return Ok(status)
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix, feature = "async-tokio-net"))]
+mod test {
+    use futures::{StreamExt, TryStreamExt};
+    use qapi_qga::GuestExecOutput;
+    use crate::guest_exec::test::{FakeGuest, command, serve};
+    use crate::futures::QgaStreamTokio;
+
+    #[test]
+    fn guest_exec_stream() {
+        let guest = FakeGuest::default();
+        let (client, server) = serve(&guest);
+        client.set_nonblocking(true).unwrap();
+
+        let runtime = ::tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let client = ::tokio::net::UnixStream::from_std(client).unwrap();
+            let (service, events) = QgaStreamTokio::open(client).into_parts();
+            let events = ::tokio::spawn(events);
+
+            let command = command();
+            let mut stream = Box::pin(service.guest_exec_stream(&command));
+            match stream.next().await.unwrap().unwrap() {
+                GuestExecOutput::Stdout(data) => assert_eq!(data, b"hello\n"),
+                output => panic!("unexpected {:?}", output),
+            }
+            // the output arrived while the process was still running
+            assert!(!guest.0.lock().unwrap().exited);
+
+            let outputs = stream.try_collect::<Vec<_>>().await.unwrap();
+            assert!(matches!(&outputs[..], [GuestExecOutput::Stderr(data), GuestExecOutput::Exited(status)]
+                if data == b"oops" && status.exitcode == Some(0)
+            ));
+
+            events.abort();
+        });
+
+        // the socket closes once the aborted events task is dropped with the runtime
+        drop(runtime);
+        server.join().unwrap();
+        let state = guest.0.lock().unwrap();
+        assert!(state.files.is_empty());
+        assert!(state.handles.is_empty());
+    }
+}
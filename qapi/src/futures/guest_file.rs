@@ -0,0 +1,350 @@
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Sink, ready};
+use futures::future::BoxFuture;
+use futures::io::{AsyncRead, AsyncWrite, AsyncSeek};
+use log::warn;
+use qapi_qga::{
+    guest_file_open, guest_file_close, guest_file_read, guest_file_write, guest_file_seek, guest_file_flush,
+    GuestFileRead, GuestFileWrite, GuestFileSeek,
+};
+use crate::guest_file::{guest_file_seek, GUEST_FILE_CHUNK_SIZE};
+use crate::{Empty, Execute, ExecuteError};
+use super::QapiService;
+
+/// The guest file commands that a [`GuestFile`] sends
+pub trait GuestFileSink:
+    Sink<Execute<guest_file_open, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_close, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_read, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_write, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_seek, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_flush, u32>, Error=io::Error> +
+    Unpin + Send + 'static
+{ }
+
+impl<W> GuestFileSink for W where W:
+    Sink<Execute<guest_file_open, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_close, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_read, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_write, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_seek, u32>, Error=io::Error> +
+    Sink<Execute<guest_file_flush, u32>, Error=io::Error> +
+    Unpin + Send + 'static
+{ }
+
+type Operation<'a, T> = BoxFuture<'a, Result<T, ExecuteError>>;
+
+enum State<'a> {
+    Idle,
+    Reading(Operation<'a, GuestFileRead>),
+    Writing(Operation<'a, GuestFileWrite>),
+    Seeking(Operation<'a, GuestFileSeek>),
+    /// Moves back over data that was read but not yet consumed, before a write
+    Rewinding(Operation<'a, GuestFileSeek>),
+    Flushing(Operation<'a, Empty>),
+    Closing(Operation<'a, Empty>),
+    Closed,
+}
+
+impl<'a> State<'a> {
+    fn busy(&self) -> io::Error {
+        match self {
+            State::Closing(..) | State::Closed => io::Error::new(io::ErrorKind::NotConnected, "guest file is closed"),
+            _ => io::Error::other("another guest file operation is in progress"),
+        }
+    }
+}
+
+/// A file opened within the guest by `guest-file-open`
+///
+/// An operation that returns `Pending` must be polled to completion before another is started, as
+/// [`AsyncWrite::poll_write`] will otherwise be told how much of the first write succeeded.
+///
+/// The handle is closed by `poll_close`. It is otherwise closed in the background on drop when the
+/// `async-tokio-spawn` feature is enabled, and leaked if not.
+pub struct GuestFile<'a, W> {
+    service: &'a QapiService<W>,
+    handle: i64,
+    chunk_size: usize,
+    state: State<'a>,
+    /// Data that was read, but that did not fit in the caller's buffer
+    buffer: Vec<u8>,
+    close_on_drop: Option<Box<dyn FnOnce(i64) + Send + 'a>>,
+}
+
+impl<'a, W: GuestFileSink> GuestFile<'a, W> {
+    /// Opens `path` with an `fopen()` style `mode`, such as `r` or `w`
+    pub async fn open(service: &'a QapiService<W>, path: &str, mode: &str) -> Result<Self, ExecuteError> {
+        let handle = service.execute(guest_file_open {
+            path: path.into(),
+            mode: Some(mode.into()),
+        }).await?;
+
+        Ok(GuestFile {
+            service,
+            handle,
+            chunk_size: GUEST_FILE_CHUNK_SIZE,
+            state: State::Idle,
+            buffer: Vec::new(),
+            close_on_drop: Some(Box::new(move |handle| {
+                let close = service.execute(guest_file_close { handle });
+                #[cfg(feature = "async-tokio-spawn")]
+                if let Ok(runtime) = ::tokio::runtime::Handle::try_current() {
+                    runtime.spawn(async move {
+                        if let Err(e) = close.await {
+                            warn!("failed to close guest file handle {}: {}", handle, e);
+                        }
+                    });
+                    return
+                }
+
+                drop(close);
+                warn!("guest file handle {} was dropped without being closed", handle);
+            })),
+        })
+    }
+
+    pub async fn close(mut self) -> Result<(), ExecuteError> {
+        self.close_on_drop = None;
+        self.state = State::Closed;
+        self.service.execute(guest_file_close { handle: self.handle }).await
+            .map(drop)
+    }
+
+    fn poll_read_(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        loop {
+            if !self.buffer.is_empty() {
+                let len = buf.len().min(self.buffer.len());
+                buf[..len].copy_from_slice(&self.buffer[..len]);
+                self.buffer.drain(..len);
+                return Poll::Ready(Ok(len))
+            }
+
+            match self.state {
+                State::Idle => {
+                    let count = buf.len().min(self.chunk_size);
+                    if count == 0 {
+                        return Poll::Ready(Ok(0))
+                    }
+                    self.state = State::Reading(Box::pin(self.service.execute(guest_file_read {
+                        handle: self.handle,
+                        count: Some(count as i64),
+                    })));
+                },
+                State::Reading(ref mut read) => {
+                    let res = ready!(read.as_mut().poll(cx));
+                    self.state = State::Idle;
+                    let read = res?;
+                    if read.buf_b64.is_empty() {
+                        return Poll::Ready(Ok(0))
+                    }
+                    self.buffer = read.buf_b64;
+                },
+                ref state => return Poll::Ready(Err(state.busy())),
+            }
+        }
+    }
+
+    fn poll_write_(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        loop {
+            match self.state {
+                State::Idle if !self.buffer.is_empty() => {
+                    let unread = self.buffer.len() as i64;
+                    self.buffer.clear();
+                    self.state = State::Rewinding(Box::pin(self.service.execute(guest_file_seek(self.handle, SeekFrom::Current(-unread))?)));
+                },
+                State::Idle => {
+                    let count = buf.len().min(self.chunk_size);
+                    if count == 0 {
+                        return Poll::Ready(Ok(0))
+                    }
+                    self.state = State::Writing(Box::pin(self.service.execute(guest_file_write {
+                        handle: self.handle,
+                        buf_b64: buf[..count].to_owned(),
+                        count: None,
+                    })));
+                },
+                State::Rewinding(ref mut seek) => {
+                    let res = ready!(seek.as_mut().poll(cx));
+                    self.state = State::Idle;
+                    res?;
+                },
+                State::Writing(ref mut write) => {
+                    let res = ready!(write.as_mut().poll(cx));
+                    self.state = State::Idle;
+                    return Poll::Ready(Ok(res?.count as usize))
+                },
+                ref state => return Poll::Ready(Err(state.busy())),
+            }
+        }
+    }
+
+    fn poll_flush_(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        loop {
+            match self.state {
+                State::Idle => self.state = State::Flushing(Box::pin(self.service.execute(guest_file_flush {
+                    handle: self.handle,
+                }))),
+                State::Flushing(ref mut flush) => {
+                    let res = ready!(flush.as_mut().poll(cx));
+                    self.state = State::Idle;
+                    return Poll::Ready(res.map(drop).map_err(From::from))
+                },
+                ref state => return Poll::Ready(Err(state.busy())),
+            }
+        }
+    }
+
+    fn poll_close_(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        loop {
+            match self.state {
+                State::Closed => return Poll::Ready(Ok(())),
+                State::Closing(ref mut close) => {
+                    let res = ready!(close.as_mut().poll(cx));
+                    self.state = State::Closed;
+                    return Poll::Ready(res.map(drop).map_err(From::from))
+                },
+                _ => {
+                    self.close_on_drop = None;
+                    self.state = State::Closing(Box::pin(self.service.execute(guest_file_close {
+                        handle: self.handle,
+                    })));
+                },
+            }
+        }
+    }
+
+    fn poll_seek_(&mut self, cx: &mut Context, pos: SeekFrom) -> Poll<io::Result<u64>> {
+        loop {
+            match self.state {
+                State::Idle => {
+                    let pos = match pos {
+                        SeekFrom::Current(offset) => SeekFrom::Current(offset - self.buffer.len() as i64),
+                        pos => pos,
+                    };
+                    self.buffer.clear();
+                    self.state = State::Seeking(Box::pin(self.service.execute(guest_file_seek(self.handle, pos)?)));
+                },
+                State::Seeking(ref mut seek) => {
+                    let res = ready!(seek.as_mut().poll(cx));
+                    self.state = State::Idle;
+                    return Poll::Ready(Ok(res?.position as u64))
+                },
+                ref state => return Poll::Ready(Err(state.busy())),
+            }
+        }
+    }
+}
+
+impl<'a, W> GuestFile<'a, W> {
+    pub fn handle(&self) -> i64 {
+        self.handle
+    }
+
+    /// Limits the size of each transfer, which defaults to [`GUEST_FILE_CHUNK_SIZE`]
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size.max(1);
+    }
+}
+
+impl<'a, W> Drop for GuestFile<'a, W> {
+    fn drop(&mut self) {
+        if let Some(close) = self.close_on_drop.take() {
+            close(self.handle)
+        }
+    }
+}
+
+impl<'a, W: GuestFileSink> AsyncRead for GuestFile<'a, W> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_read_(cx, buf)
+    }
+}
+
+impl<'a, W: GuestFileSink> AsyncWrite for GuestFile<'a, W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_write_(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_flush_(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_close_(cx)
+    }
+}
+
+impl<'a, W: GuestFileSink> AsyncSeek for GuestFile<'a, W> {
+    fn poll_seek(self: Pin<&mut Self>, cx: &mut Context, pos: SeekFrom) -> Poll<io::Result<u64>> {
+        self.get_mut().poll_seek_(cx, pos)
+    }
+}
+
+#[cfg(feature = "tokio")]
+mod tokio_impl {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use futures::ready;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use super::{GuestFile, GuestFileSink};
+
+    impl<'a, W: GuestFileSink> AsyncRead for GuestFile<'a, W> {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+            let len = ready!(self.get_mut().poll_read_(cx, buf.initialize_unfilled()))?;
+            buf.advance(len);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl<'a, W: GuestFileSink> AsyncWrite for GuestFile<'a, W> {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.get_mut().poll_write_(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            self.get_mut().poll_flush_(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            self.get_mut().poll_close_(cx)
+        }
+    }
+}
+
+impl<W: GuestFileSink> QapiService<W> {
+    /// Opens a file within the guest, with an `fopen()` style `mode`
+    pub async fn open_guest_file(&self, path: &str, mode: &str) -> Result<GuestFile<'_, W>, ExecuteError> {
+        GuestFile::open(self, path, mode).await
+    }
+
+    /// Copies a guest file into `write`, returning the number of bytes copied
+    pub async fn copy_from_guest<T: AsyncWrite + Unpin + ?Sized>(&self, path: &str, write: &mut T) -> Result<u64, ExecuteError> {
+        let mut file = self.open_guest_file(path, "r").await?;
+        let count = futures::io::copy(&mut file, write).await?;
+        futures::io::AsyncWriteExt::close(&mut file).await?;
+        Ok(count)
+    }
+
+    /// Copies `read` into a guest file, which is created or truncated
+    pub async fn copy_to_guest<R: AsyncRead + Unpin>(&self, read: R, path: &str) -> Result<u64, ExecuteError> {
+        let mut file = self.open_guest_file(path, "w").await?;
+        let count = futures::io::copy(read, &mut file).await?;
+        futures::io::AsyncWriteExt::close(&mut file).await?;
+        Ok(count)
+    }
+
+    pub async fn read_guest_file(&self, path: &str) -> Result<Vec<u8>, ExecuteError> {
+        let mut data = Vec::new();
+        self.copy_from_guest(path, &mut data).await?;
+        Ok(data)
+    }
+
+    pub async fn write_guest_file(&self, path: &str, data: &[u8]) -> Result<(), ExecuteError> {
+        self.copy_to_guest(data, path).await
+            .map(drop)
+    }
+}
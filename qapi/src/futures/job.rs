@@ -0,0 +1,277 @@
+use std::io;
+use futures::{FutureExt, Sink, StreamExt};
+use qapi_qmp::{
+    query_jobs, job_pause, job_resume, job_cancel, job_complete, job_finalize, job_dismiss,
+    JobInfo, JobStatus, JOB_STATUS_CHANGE,
+};
+use log::warn;
+use crate::job::{job_reached, job_find, job_not_found, job_unreachable};
+use crate::{Command, Execute, ExecuteError};
+use super::{QapiService, QmpEventReceiver, QmpEventSubscription, QmpBroadcastError};
+
+/// The job commands that a [`Job`] sends
+pub trait JobSink:
+    Sink<Execute<query_jobs, u32>, Error=io::Error> +
+    Sink<Execute<job_pause, u32>, Error=io::Error> +
+    Sink<Execute<job_resume, u32>, Error=io::Error> +
+    Sink<Execute<job_cancel, u32>, Error=io::Error> +
+    Sink<Execute<job_complete, u32>, Error=io::Error> +
+    Sink<Execute<job_finalize, u32>, Error=io::Error> +
+    Sink<Execute<job_dismiss, u32>, Error=io::Error> +
+    Unpin
+{ }
+
+impl<W> JobSink for W where W:
+    Sink<Execute<query_jobs, u32>, Error=io::Error> +
+    Sink<Execute<job_pause, u32>, Error=io::Error> +
+    Sink<Execute<job_resume, u32>, Error=io::Error> +
+    Sink<Execute<job_cancel, u32>, Error=io::Error> +
+    Sink<Execute<job_complete, u32>, Error=io::Error> +
+    Sink<Execute<job_finalize, u32>, Error=io::Error> +
+    Sink<Execute<job_dismiss, u32>, Error=io::Error> +
+    Unpin
+{ }
+
+/// A handle to a running job, such as a block job, following the generic QAPI job state machine
+///
+/// Its status is tracked from `JOB_STATUS_CHANGE` events, and refreshed from `query-jobs` if the
+/// subscriber lags behind.
+pub struct Job<'a, W> {
+    service: &'a QapiService<W>,
+    events: QmpEventSubscription<QmpEventReceiver, JOB_STATUS_CHANGE>,
+    id: String,
+    status: JobStatus,
+}
+
+impl<'a, W: JobSink> Job<'a, W> {
+    /// Looks up a job that has already been started
+    ///
+    /// Status changes are only seen once `events` has subscribed, so the job is queried afterwards.
+    pub async fn new<I: Into<String>>(service: &'a QapiService<W>, events: &QmpEventReceiver, id: I) -> Result<Self, ExecuteError> {
+        let mut job = Job {
+            service,
+            events: events.clone().subscribe(),
+            id: id.into(),
+            status: JobStatus::undefined,
+        };
+        job.info().await?;
+        Ok(job)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The most recently observed status
+    pub fn status(&self) -> JobStatus {
+        self.status
+    }
+
+    async fn query(&mut self) -> Result<Option<JobInfo>, ExecuteError> {
+        let jobs = self.service.execute(query_jobs { }).await?;
+        let info = job_find(jobs, &self.id);
+        self.status = info.as_ref().map(|info| info.status).unwrap_or(JobStatus::null);
+        // status changes queued ahead of the response are already reflected in it, and any that
+        // arrived since are newer, so only the last one is kept
+        while self.try_next_status()?.is_some() { }
+        Ok(info)
+    }
+
+    /// Queries the job's progress, and refreshes its status
+    pub async fn info(&mut self) -> Result<JobInfo, ExecuteError> {
+        self.query().await?.ok_or_else(|| job_not_found(&self.id))
+    }
+
+    fn status_event(&mut self, event: Option<Result<(JOB_STATUS_CHANGE, crate::Timestamp), QmpBroadcastError>>) -> Result<Option<JobStatus>, ExecuteError> {
+        match event {
+            Some(Ok((event, _))) if event.id == self.id => {
+                self.status = event.status;
+                Ok(Some(event.status))
+            },
+            Some(Ok(..)) => Ok(None),
+            Some(Err(QmpBroadcastError::Lagged(count))) => {
+                warn!("missed {} events while tracking job {}", count, self.id);
+                Ok(None)
+            },
+            Some(Err(QmpBroadcastError::Io(e))) => Err(e.into()),
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("QMP event stream closed while tracking job {}", self.id)).into()),
+        }
+    }
+
+    /// Applies a status change that has already been received, without waiting for one
+    fn try_next_status(&mut self) -> Result<Option<JobStatus>, ExecuteError> {
+        loop {
+            match self.events.next().now_or_never() {
+                None => return Ok(None),
+                Some(event) => if let Some(status) = self.status_event(event)? {
+                    return Ok(Some(status))
+                },
+            }
+        }
+    }
+
+    /// Waits until the job's status changes
+    pub async fn next_status(&mut self) -> Result<JobStatus, ExecuteError> {
+        loop {
+            let event = self.events.next().await;
+            let lagged = matches!(event, Some(Err(QmpBroadcastError::Lagged(..))));
+            if let Some(status) = self.status_event(event)? {
+                return Ok(status)
+            }
+            if lagged {
+                let previous = self.status;
+                self.query().await?;
+                if self.status != previous {
+                    return Ok(self.status)
+                }
+            }
+        }
+    }
+
+    /// Waits until the job reaches `target`, failing if it finishes first
+    pub async fn wait_for(&mut self, target: JobStatus) -> Result<(), ExecuteError> {
+        loop {
+            if job_reached(self.status, target) {
+                return Ok(())
+            }
+            if self.status.is_finished() {
+                let info = self.query().await?;
+                return Err(job_unreachable(&self.id, info.as_ref(), target))
+            }
+            self.next_status().await?;
+        }
+    }
+
+    /// Waits until the job concludes, failing with its [`JobError`](qapi_qmp::JobError) if it has one
+    ///
+    /// The error is unavailable once the job has been dismissed, so jobs should be started with
+    /// `auto-dismiss` disabled in order to observe it.
+    pub async fn wait_concluded(&mut self) -> Result<(), ExecuteError> {
+        self.wait_for(JobStatus::concluded).await?;
+        match self.status {
+            JobStatus::concluded => match self.query().await? {
+                Some(info) => info.result().map_err(From::from),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs the job to the end, completing, finalizing and dismissing it as needed, and reports its error
+    ///
+    /// A job that becomes `ready`, such as a mirror that has caught up with its source, is completed.
+    pub async fn finish(&mut self) -> Result<(), ExecuteError> {
+        // each manual transition is requested once, then awaited until the status changes
+        let mut requested = None;
+        loop {
+            // act only on the latest status, as an automatic job may already have moved on
+            while self.try_next_status()?.is_some() { }
+
+            match self.status {
+                status if requested == Some(status) => {
+                    self.next_status().await?;
+                },
+                status @ JobStatus::ready | status @ JobStatus::pending => {
+                    requested = Some(status);
+                    let res = match status {
+                        JobStatus::ready => self.execute(job_complete { id: self.id.clone() }).await,
+                        _ => self.execute(job_finalize { id: self.id.clone() }).await,
+                    };
+                    if res.is_err() && self.query().await?.is_some_and(|info| info.status == status) {
+                        return res
+                    }
+                },
+                JobStatus::concluded => {
+                    let res = match self.query().await? {
+                        Some(info) => info.result(),
+                        None => return Ok(()),
+                    };
+                    let dismissed = self.execute(job_dismiss { id: self.id.clone() }).await;
+                    if dismissed.is_err() && self.query().await?.is_some() {
+                        return dismissed
+                    }
+                    self.status = JobStatus::null;
+                    return res.map_err(From::from)
+                },
+                JobStatus::null => return Ok(()),
+                _ => {
+                    self.next_status().await?;
+                },
+            }
+        }
+    }
+
+    async fn execute<C: Command>(&self, command: C) -> Result<(), ExecuteError> where
+        W: Sink<Execute<C, u32>, Error=io::Error>,
+    {
+        self.service.execute(command).await.map(drop)
+    }
+
+    pub async fn pause(&self) -> Result<(), ExecuteError> {
+        self.execute(job_pause { id: self.id.clone() }).await
+    }
+
+    pub async fn resume(&self) -> Result<(), ExecuteError> {
+        self.execute(job_resume { id: self.id.clone() }).await
+    }
+
+    pub async fn cancel(&self) -> Result<(), ExecuteError> {
+        self.execute(job_cancel { id: self.id.clone() }).await
+    }
+
+    /// Completes a job that is ready, such as a mirror that has caught up with its source
+    pub async fn complete(&self) -> Result<(), ExecuteError> {
+        self.execute(job_complete { id: self.id.clone() }).await
+    }
+
+    pub async fn finalize(&self) -> Result<(), ExecuteError> {
+        self.execute(job_finalize { id: self.id.clone() }).await
+    }
+
+    pub async fn dismiss(&mut self) -> Result<(), ExecuteError> {
+        self.execute(job_dismiss { id: self.id.clone() }).await?;
+        self.status = JobStatus::null;
+        Ok(())
+    }
+}
+
+impl<W: JobSink> QapiService<W> {
+    /// Tracks a job that has already been started, by its job ID
+    pub async fn job<I: Into<String>>(&self, events: &QmpEventReceiver, id: I) -> Result<Job<'_, W>, ExecuteError> {
+        Job::new(self, events, id).await
+    }
+}
+
+#[cfg(all(test, feature = "async-futures-io"))]
+mod test {
+    use qapi_qmp::{JobStatus, qmp_capabilities};
+    use crate::futures::{QmpStreamFutures, QmpEventBroadcast, QmpLagPolicy};
+    use crate::job::test::TRANSCRIPT;
+    use crate::{QapiReplay, ExecuteError};
+
+    #[test]
+    fn finish() {
+        let replay = QapiReplay::from_reader(TRANSCRIPT.as_bytes()).unwrap();
+        futures::executor::block_on(async {
+            let stream = QmpStreamFutures::open_split(replay.clone(), replay.clone()).await.unwrap().stream;
+            let (service, events) = stream.into_parts();
+            let (broadcast, receiver) = QmpEventBroadcast::new(events, 8, QmpLagPolicy::DropOldest);
+
+            let test = async {
+                service.execute(qmp_capabilities { enable: None }).await.unwrap();
+                let mut job = service.job(&receiver, "job0").await.unwrap();
+                assert_eq!(job.status(), JobStatus::ready);
+                assert_eq!(job.try_next_status().unwrap(), None);
+                match job.finish().await {
+                    Err(ExecuteError::Job(e)) => assert_eq!(e.error, "boom"),
+                    res => panic!("unexpected {:?}", res),
+                }
+                assert_eq!(job.status(), JobStatus::null);
+            };
+            // the broadcast ends once the transcript has been read to the end
+            let (res, ()) = futures::join!(broadcast, test);
+            res.unwrap();
+        });
+        replay.finish().unwrap();
+    }
+}
@@ -2,32 +2,89 @@
 use qapi_qmp::{QmpMessage, QmpMessageAny, QapiCapabilities, QMPCapability};
 
 use qapi_spec::Response;
-use crate::{Any, Execute, ExecuteResult, Command};
+use crate::{Any, Execute, ExecuteOob, ExecuteRaw, ExecuteResult, ExecuteError, Command, OobCommand};
 
+use std::borrow::Cow;
 use std::collections::BTreeMap;
+#[cfg(feature = "qapi-qmp")]
+use std::collections::VecDeque;
 use std::convert::TryInto;
 use std::marker::Unpin;
 use std::sync::{Arc, Mutex as StdMutex, atomic::{AtomicUsize, AtomicBool, Ordering}};
-use std::task::{Context, Poll};
+use std::task::{Context, Poll, Waker};
 use std::pin::Pin;
 use std::io;
+#[cfg(feature = "async-tokio-time")]
+use std::time::Duration;
 use futures::channel::oneshot;
 use futures::task::AtomicWaker;
-use futures::lock::Mutex;
+use futures::lock::{Mutex, OwnedMutexGuard};
 use futures::{Future, FutureExt, Sink, SinkExt, Stream};
+use futures::future::Either;
 use serde::Deserialize;
 use log::{trace, info, warn};
 
-#[cfg(feature = "tokio-util")]
+#[cfg(any(feature = "tokio-util", feature = "async-futures-io"))]
 mod codec;
+#[cfg(any(feature = "tokio-util", feature = "async-futures-io"))]
+pub use self::codec::JsonLinesCodec;
+#[cfg(any(feature = "tokio-util", feature = "async-futures-io"))]
+mod resync;
 
 #[cfg(feature = "tokio")]
 mod tokio;
 #[cfg(feature = "tokio")]
 pub use self::tokio::*;
 
+#[cfg(feature = "async-futures-io")]
+mod futures_io;
+#[cfg(feature = "async-futures-io")]
+pub use self::futures_io::*;
+
 #[cfg(feature = "tower-service")]
 mod tower;
+#[cfg(feature = "tower-service")]
+pub use self::tower::{QapiTowerService, QapiRequest, DEFAULT_MAX_IN_FLIGHT};
+
+#[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+mod server;
+#[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
+pub use self::server::*;
+
+#[cfg(all(feature = "qapi-qga", feature = "async-tokio-time"))]
+mod guest_exec;
+#[cfg(all(feature = "qapi-qga", feature = "async-tokio-time"))]
+pub use self::guest_exec::GuestExecSink;
+
+#[cfg(feature = "qapi-qga")]
+mod guest_file;
+#[cfg(feature = "qapi-qga")]
+pub use self::guest_file::{GuestFile, GuestFileSink};
+
+#[cfg(feature = "qapi-qga")]
+mod fsfreeze;
+#[cfg(feature = "qapi-qga")]
+pub use self::fsfreeze::{GuestFsfreeze, GuestFsfreezeSink};
+
+#[cfg(feature = "qapi-qmp")]
+mod subscription;
+#[cfg(feature = "qapi-qmp")]
+pub use self::subscription::QmpEventSubscription;
+
+#[cfg(feature = "qapi-qmp")]
+mod broadcast;
+#[cfg(feature = "qapi-qmp")]
+pub use self::broadcast::{QmpEventBroadcast, QmpEventReceiver, QmpLagPolicy, QmpBroadcastError};
+
+#[cfg(feature = "qapi-qmp")]
+mod job;
+#[cfg(feature = "qapi-qmp")]
+pub use self::job::{Job, JobSink};
+
+#[cfg(all(feature = "qapi-qmp", feature = "async-tokio-spawn", feature = "async-tokio-time"))]
+mod reconnect;
+#[cfg(all(feature = "qapi-qmp", feature = "async-tokio-spawn", feature = "async-tokio-time"))]
+pub use self::reconnect::{QmpReconnectHandle, QmpReconnectConfig, QmpReconnectEvent};
 
 pub struct QapiStream<R, W> {
     service: QapiService<W>,
@@ -46,6 +103,23 @@ impl<R, W> QapiStream<R, W> {
         (self.service, self.events)
     }
 
+    /// The version advertised by the QMP greeting, once capabilities have been negotiated
+    #[cfg(feature = "qapi-qmp")]
+    pub fn qemu_version(&self) -> Option<qapi_qmp::QemuVersion> {
+        self.service.qemu_version()
+    }
+
+    pub fn set_unknown_response_policy(&mut self, policy: QapiUnknownResponsePolicy) {
+        self.events.set_unknown_response_policy(policy)
+    }
+
+    /// Limits how many events are retained while executing commands, until they are read from the
+    /// event stream. Defaults to [`DEFAULT_EVENT_BUFFER_CAPACITY`].
+    #[cfg(feature = "qapi-qmp")]
+    pub fn set_event_buffer_capacity(&mut self, capacity: usize) {
+        self.events.set_event_buffer_capacity(capacity)
+    }
+
     #[cfg(feature = "async-tokio-spawn")]
     pub fn spawn_tokio(self) -> (QapiService<W>, ::tokio::task::JoinHandle<()>) where
         QapiEvents<R>: Future<Output=io::Result<()>> + Send + 'static,
@@ -88,10 +162,15 @@ impl<S, W> QmpStreamNegotiation<S, W> where
     pub async fn negotiate_caps<C>(mut self, caps: C) -> io::Result<QapiStream<S, W>> where
         C: IntoIterator<Item=QMPCapability>,
     {
+        let caps: Vec<_> = caps.into_iter().collect();
+        let enable_oob = caps.contains(&QMPCapability::oob);
         let _ = self.stream.execute(qapi_qmp::qmp_capabilities {
-            enable: Some(caps.into_iter().collect()),
+            enable: Some(caps),
         }).await?;
 
+        let shared = &self.stream.service.shared;
+        shared.oob.store(enable_oob, Ordering::Relaxed);
+        *shared.version.lock().unwrap() = Some(self.capabilities.qemu_version());
         Ok(self.stream)
     }
 
@@ -100,73 +179,261 @@ impl<S, W> QmpStreamNegotiation<S, W> where
     }
 }
 
-type QapiCommandMap = BTreeMap<u32, oneshot::Sender<Result<Any, qapi_spec::Error>>>;
+type QapiCommandResult = Result<Any, ExecuteError>;
+type QapiCommandMap = BTreeMap<u32, oneshot::Sender<QapiCommandResult>>;
 
+/// Executes commands over the write half of a QAPI stream
+///
+/// QMP commands always carry an ID, so any number of them may be in flight at once, and each
+/// response is matched to its command by ID. QEMU still runs in-band commands one at a time, in
+/// the order they were written, and only `exec-oob` commands may overtake them. Guest agent
+/// commands carry no ID, so each one holds the write half until its response arrives, and
+/// concurrent callers are served strictly one after another.
 pub struct QapiService<W> {
     shared: Arc<QapiShared>,
     write: Arc<Mutex<W>>,
     id_counter: AtomicUsize,
+    #[cfg(feature = "async-tokio-time")]
+    timeout: Option<Duration>,
 }
 
 impl<W> QapiService<W> {
-    #[cfg(feature = "tokio")]
+    #[cfg(any(feature = "tokio", feature = "async-futures-io"))]
     fn new(write: W, shared: Arc<QapiShared>) -> Self {
         QapiService {
             shared,
             write: Mutex::new(write).into(),
             id_counter: AtomicUsize::new(0),
+            #[cfg(feature = "async-tokio-time")]
+            timeout: None,
         }
     }
 
-    fn next_oob_id(&self) -> u32 {
+    /// The default deadline applied to every command executed through this service
+    #[cfg(feature = "async-tokio-time")]
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// The version advertised by the QMP greeting, once capabilities have been negotiated
+    #[cfg(feature = "qapi-qmp")]
+    pub fn qemu_version(&self) -> Option<qapi_qmp::QemuVersion> {
+        *self.shared.version.lock().unwrap()
+    }
+
+    #[cfg(feature = "async-tokio-time")]
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    #[cfg(feature = "async-tokio-time")]
+    fn default_deadline(&self) -> Option<::tokio::time::Sleep> {
+        self.timeout.map(::tokio::time::sleep)
+    }
+
+    #[cfg(not(feature = "async-tokio-time"))]
+    fn default_deadline(&self) -> Option<futures::future::Pending<()>> {
+        None
+    }
+
+    fn next_id(&self) -> u32 {
         self.id_counter.fetch_add(1, Ordering::Relaxed) as _
     }
 
     fn command_id(&self) -> Option<u32> {
-        if self.shared.supports_oob {
-            Some(self.next_oob_id())
+        if self.shared.command_ids {
+            Some(self.next_id())
         } else {
             None
         }
     }
 
-    fn command_response<C: Command>(receiver: oneshot::Receiver<Result<Any, qapi_spec::Error>>) -> impl Future<Output=ExecuteResult<C>> {
+    fn command_response(receiver: &mut oneshot::Receiver<QapiCommandResult>) -> impl Future<Output=QapiCommandResult> + '_ {
         receiver.map(|res| match res {
-            Ok(Ok(res)) => C::Ok::deserialize(&res)
-                .map_err(io::Error::from).map_err(From::from),
-            Ok(Err(e)) => Err(e.into()),
+            Ok(res) => res,
             Err(_cancelled) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "QAPI stream disconnected").into()),
         })
     }
 
-    pub fn execute<C: Command>(&self, command: C) -> impl Future<Output=ExecuteResult<C>> where
-        W: Sink<Execute<C, u32>, Error=io::Error> + Unpin
+    fn command_decode<C: Command>(res: QapiCommandResult) -> ExecuteResult<C> {
+        res.and_then(|res| C::Ok::deserialize(&res)
+            .map_err(io::Error::from).map_err(From::from)
+        )
+    }
+
+    async fn command_deadline<F, D>(name: Cow<'static, str>, execute: F, deadline: Option<D>) -> QapiCommandResult where
+        F: Future<Output=QapiCommandResult>,
+        D: Future<Output=()>,
     {
-        let id = self.command_id();
-        let sink = self.write.clone();
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return execute.await,
+        };
+
+        futures::pin_mut!(execute);
+        futures::pin_mut!(deadline);
+        match futures::future::select(execute, deadline).await {
+            Either::Left((res, _)) => res,
+            // dropping the command releases its pending entry
+            Either::Right(((), _)) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("QAPI command {} timed out", name)).into()),
+        }
+    }
+
+    /// Sends a command once `lock` acquires the write half, and resolves to its undecoded response
+    ///
+    /// `command` builds the request from its assigned id, which is taken from `reserved` if the
+    /// command already holds a place among those in flight.
+    fn execute_locked<T, F, L, D>(&self, lock: L, reserved: Option<QapiPendingCommand>, name: Cow<'static, str>, command: F, deadline: Option<D>) -> impl Future<Output=QapiCommandResult> where
+        W: Sink<T, Error=io::Error> + Unpin,
+        F: FnOnce(Option<u32>) -> T,
+        L: Future<Output=OwnedMutexGuard<W>>,
+        D: Future<Output=()>,
+    {
+        let id = match &reserved {
+            Some(pending) => Some(pending.id),
+            None => self.command_id(),
+        };
         let shared = self.shared.clone();
-        let command = Execute::new(command, id);
+        let command = command(id);
 
-        async move {
-            let mut sink = sink.lock().await;
-            let receiver = shared.command_insert(id.unwrap_or_default());
+        let execute = async move {
+            let mut sink = lock.await;
+            let mut pending = match reserved {
+                Some(pending) => pending,
+                None => shared.command_insert(id.unwrap_or_default()),
+            };
 
-            sink.send(command).await?;
+            sink.feed(command).await?;
+            pending.sent = true;
+            sink.flush().await?;
             if id.is_some() {
-                // retain write lock only if id/oob execution isn't supported
+                // retain write lock only if commands can't be matched to responses by id
                 drop(sink)
             }
 
-            Self::command_response::<C>(receiver).await
+            Self::command_response(&mut pending.receiver).await
+        };
+
+        Self::command_deadline(name, execute, deadline)
+    }
+
+    fn execute_<C: Command, D: Future<Output=()>>(&self, command: C, deadline: Option<D>) -> impl Future<Output=ExecuteResult<C>> where
+        W: Sink<Execute<C, u32>, Error=io::Error> + Unpin
+    {
+        self.execute_locked(self.write.clone().lock_owned(), None, C::NAME.into(), |id| Execute::new(command, id), deadline)
+            .map(Self::command_decode::<C>)
+    }
+
+    /// Executes a command, subject to the service's default timeout if one is set
+    pub fn execute<C: Command>(&self, command: C) -> impl Future<Output=ExecuteResult<C>> where
+        W: Sink<Execute<C, u32>, Error=io::Error> + Unpin
+    {
+        self.execute_(command, self.default_deadline())
+    }
+
+    /// Executes a command, failing with `TimedOut` once `deadline` resolves
+    ///
+    /// The command's pending entry is released on timeout, and any response that arrives later is discarded.
+    pub fn execute_until<C: Command, D: Future<Output=()>>(&self, command: C, deadline: D) -> impl Future<Output=ExecuteResult<C>> where
+        W: Sink<Execute<C, u32>, Error=io::Error> + Unpin
+    {
+        self.execute_(command, Some(deadline))
+    }
+
+    #[cfg(feature = "async-tokio-time")]
+    pub fn execute_timeout<C: Command>(&self, command: C, timeout: Duration) -> impl Future<Output=ExecuteResult<C>> where
+        W: Sink<Execute<C, u32>, Error=io::Error> + Unpin
+    {
+        self.execute_until(command, ::tokio::time::sleep(timeout))
+    }
+
+    /// Executes a command by name, including any that the schema does not describe
+    ///
+    /// Null `arguments` are omitted from the request, and the response is returned undecoded.
+    pub fn execute_raw<N: Into<String>>(&self, name: N, arguments: Any) -> impl Future<Output=Result<Any, ExecuteError>> where
+        W: Sink<ExecuteRaw<u32>, Error=io::Error> + Unpin
+    {
+        let name = name.into();
+        self.execute_locked(self.write.clone().lock_owned(), None, name.clone().into(), |id| ExecuteRaw::new(name, arguments, id), self.default_deadline())
+    }
+
+    /// Introspects the commands, events and types that QEMU supports
+    #[cfg(feature = "qapi-qmp")]
+    pub fn query_schema(&self) -> impl Future<Output=Result<qapi_qmp::QmpSchema, ExecuteError>> where
+        W: Sink<ExecuteRaw<u32>, Error=io::Error> + Unpin
+    {
+        self.execute_raw("query-qmp-schema", Any::Null).map(|res| res.and_then(|schema|
+            qapi_qmp::QmpSchema::deserialize(schema).map_err(|e| io::Error::from(e).into())
+        ))
+    }
+
+    /// Runs an HMP command, failing with `CommandNotFound` if this QEMU build lacks it
+    #[cfg(feature = "qapi-qmp")]
+    pub fn hmp<S: Into<String>>(&self, command_line: S) -> impl Future<Output=Result<String, ExecuteError>> where
+        W: Sink<Execute<qapi_qmp::human_monitor_command, u32>, Error=io::Error> + Unpin
+    {
+        let command = qapi_qmp::human_monitor_command::new(command_line);
+        let command_line = command.command_line.clone();
+        self.execute(command).map(move |res| res.and_then(|output|
+            qapi_qmp::hmp_output(&command_line, output).map_err(From::from)
+        ))
+    }
+
+    /// Runs and parses an HMP `info` command
+    #[cfg(feature = "qapi-qmp")]
+    pub fn hmp_info<T: qapi_qmp::HmpInfo>(&self) -> impl Future<Output=Result<T, ExecuteError>> where
+        W: Sink<Execute<qapi_qmp::human_monitor_command, u32>, Error=io::Error> + Unpin
+    {
+        self.hmp(T::COMMAND).map(|res| res.and_then(|output|
+            T::parse(&output).map_err(From::from)
+        ))
+    }
+
+    /// Executes a `transaction`, reporting which action caused it to fail
+    #[cfg(feature = "qapi-qmp")]
+    pub fn transaction(&self, transaction: &crate::QmpTransaction) -> impl Future<Output=Result<(), crate::QmpTransactionError>> where
+        W: Sink<Execute<qapi_qmp::transaction, u32>, Error=io::Error> + Unpin
+    {
+        let execute = transaction.command().map(|command| self.execute(command));
+        let transaction = transaction.clone();
+        async move {
+            transaction.result(execute?.await)
         }
     }
 
-    /*pub async fn execute_oob<C: Command>(&self, command: C) -> io::Result<ExecuteResult<C>> {
-        /* TODO: should we assert C::ALLOW_OOB here and/or at the type level?
-         * If oob isn't supported should we fall back to serial execution or error?
-         */
-        self.execute_(command, true).await
-    }*/
+    /// Executes a command via `exec-oob`, bypassing any in-band commands that are still in flight
+    ///
+    /// Fails without sending it unless the `oob` capability was enabled during capabilities
+    /// negotiation, which [`QmpStreamNegotiation::negotiate`] does not do.
+    pub fn execute_oob<C: OobCommand>(&self, command: C) -> impl Future<Output=ExecuteResult<C>> where
+        W: Sink<ExecuteOob<C, u32>, Error=io::Error> + Unpin
+    {
+        let id = self.next_id();
+        let sink = self.write.clone();
+        let shared = self.shared.clone();
+        let command = ExecuteOob::new(command, id);
+
+        let execute = async move {
+            if !shared.oob.load(Ordering::Relaxed) {
+                return Err(io::Error::new(io::ErrorKind::Unsupported,
+                    format!("QMP capability oob was not enabled, so {} cannot be executed out-of-band", C::NAME)
+                ).into())
+            }
+
+            let mut sink = sink.lock().await;
+            let mut pending = shared.command_insert(id);
+
+            sink.feed(command).await?;
+            pending.sent = true;
+            sink.flush().await?;
+            drop(sink);
+
+            Self::command_response(&mut pending.receiver).await
+        };
+
+        Self::command_deadline(C::NAME.into(), execute, self.default_deadline())
+            .map(Self::command_decode::<C>)
+    }
 
     #[cfg(feature = "qapi-qga")]
     pub fn guest_sync(&self, sync_value: i32) -> impl Future<Output=Result<(), crate::ExecuteError>> where
@@ -182,6 +449,22 @@ impl<W> QapiService<W> {
         }))
     }
 
+    /// Like `guest_sync`, but first discards any stale or partial data that may be
+    /// left in the channel from an earlier, interrupted exchange
+    #[cfg(feature = "qapi-qga")]
+    pub fn guest_sync_delimited(&self, sync_value: i32) -> impl Future<Output=Result<(), crate::ExecuteError>> where
+        W: Sink<Execute<qapi_qga::guest_sync_delimited, u32>, Error=io::Error> + Unpin
+    {
+        let id = sync_value.into();
+        self.execute(qapi_qga::guest_sync_delimited {
+            id,
+        }).map(move |res| res.and_then(|res| if res == id {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "QGA sync failed").into())
+        }))
+    }
+
     fn stop(&self) {
         let mut commands = self.shared.commands.lock().unwrap();
         if self.shared.abandoned.load(Ordering::Relaxed) {
@@ -197,29 +480,68 @@ impl<W> Drop for QapiService<W> {
     }
 }
 
+/// What happens to a response that does not belong to any command in flight
+///
+/// Responses to commands whose futures were dropped are always discarded, and are not affected by this.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum QapiUnknownResponsePolicy {
+    /// Log a warning and discard the response
+    #[default]
+    Warn,
+    /// Silently discard the response
+    Discard,
+    /// Fail the event stream, which disconnects every pending command
+    Fail,
+}
+
 #[derive(Default)]
 struct QapiSharedCommands {
     pending: QapiCommandMap,
+    // responses still expected for commands whose caller has given up on them
+    orphaned: BTreeMap<u32, usize>,
+    // callers waiting for a command to complete before they can send another
+    waiters: Vec<Waker>,
     abandoned: bool,
 }
 
+impl QapiSharedCommands {
+    fn wake_waiters(&mut self) {
+        for waker in self.waiters.drain(..) {
+            waker.wake()
+        }
+    }
+}
+
 struct QapiShared {
     commands: StdMutex<QapiSharedCommands>,
     stop_waker: AtomicWaker,
     stop: AtomicBool,
     abandoned: AtomicBool,
-    supports_oob: bool,
+    // decode errors fail pending commands rather than the stream, as the transport resynchronises itself
+    resync: AtomicBool,
+    unknown_responses: StdMutex<QapiUnknownResponsePolicy>,
+    // every command carries an id, so several may be in flight at once
+    command_ids: bool,
+    // enabled by capabilities negotiation, rather than merely advertised by the greeting
+    oob: AtomicBool,
+    #[cfg(feature = "qapi-qmp")]
+    version: StdMutex<Option<qapi_qmp::QemuVersion>>,
 }
 
 impl QapiShared {
-    #[cfg(feature = "tokio")]
-    fn new(supports_oob: bool) -> Self {
+    #[cfg(any(feature = "tokio", feature = "async-futures-io"))]
+    fn new(command_ids: bool) -> Self {
         Self {
             commands: Default::default(),
             stop_waker: Default::default(),
             stop: Default::default(),
             abandoned: Default::default(),
-            supports_oob,
+            resync: Default::default(),
+            unknown_responses: Default::default(),
+            command_ids,
+            oob: Default::default(),
+            #[cfg(feature = "qapi-qmp")]
+            version: Default::default(),
         }
     }
 
@@ -256,21 +578,199 @@ impl QapiShared {
         }
     }
 
-    fn command_remove(&self, id: u32) -> Option<oneshot::Sender<Result<Any, qapi_spec::Error>>> {
+    fn command_remove(&self, id: u32) -> Option<QapiCommandResponse> {
         let mut commands = self.commands.lock().unwrap();
-        commands.pending.remove(&id)
+        // orphans must come first: without IDs, a late response precedes that of any newer command
+        if let Some(count) = commands.orphaned.get_mut(&id) {
+            *count -= 1;
+            if *count == 0 {
+                commands.orphaned.remove(&id);
+            }
+            commands.wake_waiters();
+            return Some(QapiCommandResponse::Orphaned)
+        }
+        let res = commands.pending.remove(&id).map(QapiCommandResponse::Pending);
+        commands.wake_waiters();
+        res
     }
 
-    fn command_insert(&self, id: u32) -> oneshot::Receiver<Result<Any, qapi_spec::Error>> {
-        let (sender, receiver) = oneshot::channel();
+    /// Waits until fewer than `limit` commands are in flight and `id` is free to be reused, then
+    /// reserves a pending entry for it
+    ///
+    /// Orphaned commands are not counted, as their responses may never arrive.
+    #[cfg(feature = "tower-service")]
+    fn poll_command_slot(self: &Arc<Self>, cx: &mut Context, id: Option<u32>, limit: usize) -> Poll<Option<QapiPendingCommand>> {
         let mut commands = self.commands.lock().unwrap();
+        let busy = id.is_some_and(|id| commands.pending.contains_key(&id) || commands.orphaned.contains_key(&id))
+            || commands.pending.len() >= limit;
+        if busy && !commands.abandoned {
+            if !commands.waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+                commands.waiters.push(cx.waker().clone());
+            }
+            Poll::Pending
+        } else {
+            Poll::Ready(id.map(|id| self.command_insert_locked(&mut commands, id)))
+        }
+    }
+
+    fn command_insert(self: &Arc<Self>, id: u32) -> QapiPendingCommand {
+        let mut commands = self.commands.lock().unwrap();
+        self.command_insert_locked(&mut commands, id)
+    }
+
+    fn command_insert_locked(self: &Arc<Self>, commands: &mut QapiSharedCommands, id: u32) -> QapiPendingCommand {
+        let (sender, receiver) = oneshot::channel();
         if !commands.abandoned {
             // otherwise sender is dropped immediately
             if let Some(_prev) = commands.pending.insert(id, sender) {
                 panic!("QAPI duplicate command id {:?}, this should not happen", id);
             }
         }
-        receiver
+        QapiPendingCommand {
+            shared: self.clone(),
+            id,
+            sent: false,
+            receiver,
+        }
+    }
+
+    fn unknown_response(&self, e: io::Error) -> io::Result<()> {
+        match *self.unknown_responses.lock().unwrap() {
+            QapiUnknownResponsePolicy::Warn => {
+                warn!("Discarding {}", e);
+                Ok(())
+            },
+            QapiUnknownResponsePolicy::Discard => {
+                trace!("Discarding {}", e);
+                Ok(())
+            },
+            QapiUnknownResponsePolicy::Fail => Err(e),
+        }
+    }
+
+    fn is_recoverable(&self, e: &io::Error) -> bool {
+        e.kind() == io::ErrorKind::InvalidData && self.resync.load(Ordering::Relaxed)
+    }
+
+    /// Fails all pending commands after their responses were lost to a decode error
+    fn command_fail_all(&self, e: &io::Error) {
+        let mut commands = self.commands.lock().unwrap();
+        for (_, sender) in std::mem::take(&mut commands.pending) {
+            let _ = sender.send(Err(io::Error::new(e.kind(), e.to_string()).into()));
+        }
+        commands.orphaned.clear();
+        commands.wake_waiters();
+    }
+
+    fn command_abandon(&self, id: u32, sent: bool) {
+        let mut commands = self.commands.lock().unwrap();
+        if commands.pending.remove(&id).is_some() && sent {
+            *commands.orphaned.entry(id).or_default() += 1;
+        }
+        commands.wake_waiters();
+    }
+}
+
+enum QapiCommandResponse {
+    Pending(oneshot::Sender<QapiCommandResult>),
+    Orphaned,
+}
+
+/// Releases a command's pending entry if it is dropped before a response arrives
+struct QapiPendingCommand {
+    shared: Arc<QapiShared>,
+    id: u32,
+    sent: bool,
+    receiver: oneshot::Receiver<QapiCommandResult>,
+}
+
+impl Drop for QapiPendingCommand {
+    fn drop(&mut self) {
+        self.shared.command_abandon(self.id, self.sent);
+    }
+}
+
+/// The number of events that [`QapiEvents`] retains while it is driven as a `Future`
+#[cfg(feature = "qapi-qmp")]
+pub const DEFAULT_EVENT_BUFFER_CAPACITY: usize = 256;
+
+/// Events that arrived while [`QapiEvents`] was driven as a `Future`, such as during
+/// [`QapiStream::execute`], which are yielded first once it is read as a `Stream`
+#[cfg(feature = "qapi-qmp")]
+struct QmpEventBuffer {
+    queue: VecDeque<qapi_qmp::Event>,
+    capacity: usize,
+    overflowed: bool,
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl QmpEventBuffer {
+    fn push(&mut self, event: qapi_qmp::Event) {
+        if self.capacity == 0 {
+            trace!("Ignoring QAPI event");
+            return
+        }
+
+        if self.queue.len() >= self.capacity {
+            if !self.overflowed {
+                warn!("QMP event buffer is full, discarding the oldest events");
+                self.overflowed = true;
+            }
+            self.queue.pop_front();
+        }
+        self.queue.push_back(event);
+    }
+
+    fn pop(&mut self) -> Option<qapi_qmp::Event> {
+        let event = self.queue.pop_front();
+        if self.queue.is_empty() {
+            self.overflowed = false;
+        }
+        event
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl Default for QmpEventBuffer {
+    fn default() -> Self {
+        Self {
+            queue: Default::default(),
+            capacity: DEFAULT_EVENT_BUFFER_CAPACITY,
+            overflowed: false,
+        }
+    }
+}
+
+/// A message read from a QAPI stream, which is routed to its command if it is a response
+pub trait QapiMessage: Sized {
+    fn into_response(self) -> Result<Response<Any>, Self>;
+
+    #[cfg(feature = "qapi-qmp")]
+    fn into_event(self) -> Option<qapi_qmp::Event> {
+        None
+    }
+}
+
+impl QapiMessage for Response<Any> {
+    fn into_response(self) -> Result<Response<Any>, Self> {
+        Ok(self)
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl QapiMessage for QmpMessageAny {
+    fn into_response(self) -> Result<Response<Any>, Self> {
+        match self {
+            QmpMessage::Response(res) => Ok(res),
+            message => Err(message),
+        }
+    }
+
+    fn into_event(self) -> Option<qapi_qmp::Event> {
+        match self {
+            QmpMessage::Event(e) => Some(e),
+            QmpMessage::Response(..) => None,
+        }
     }
 }
 
@@ -278,9 +778,41 @@ impl QapiShared {
 pub struct QapiEvents<S> {
     stream: S,
     shared: Arc<QapiShared>,
+    #[cfg(feature = "qapi-qmp")]
+    buffer: QmpEventBuffer,
 }
 
 impl<S> QapiEvents<S> {
+    #[cfg(any(feature = "tokio", feature = "async-futures-io"))]
+    fn new(stream: S, shared: Arc<QapiShared>) -> Self {
+        Self {
+            stream,
+            shared,
+            #[cfg(feature = "qapi-qmp")]
+            buffer: Default::default(),
+        }
+    }
+
+    /// Limits how many events are retained while this is driven as a `Future`, until they are
+    /// read from the `Stream`. Defaults to [`DEFAULT_EVENT_BUFFER_CAPACITY`], and the oldest
+    /// events are discarded once it is full.
+    #[cfg(feature = "qapi-qmp")]
+    pub fn set_event_buffer_capacity(&mut self, capacity: usize) {
+        self.buffer.capacity = capacity;
+        while self.buffer.queue.len() > capacity {
+            self.buffer.queue.pop_front();
+        }
+    }
+
+    pub fn set_unknown_response_policy(&self, policy: QapiUnknownResponsePolicy) {
+        *self.shared.unknown_responses.lock().unwrap() = policy;
+    }
+
+    fn discard_events(&mut self) {
+        #[cfg(feature = "qapi-qmp")]
+        self.set_event_buffer_capacity(0);
+    }
+
     pub fn release(&self) -> Result<(), ()> {
         let commands = self.shared.commands.lock().unwrap();
         if commands.abandoned {
@@ -291,9 +823,12 @@ impl<S> QapiEvents<S> {
         }
     }
 
-    pub async fn into_future(self) -> () where
+    pub async fn into_future(mut self) -> () where
         Self: Future<Output=io::Result<()>>,
     {
+        // nothing can read buffered events once the stream is consumed
+        self.discard_events();
+
         if self.release().is_err() {
             info!("QAPI service abandoned before spawning");
             return
@@ -324,16 +859,37 @@ impl<S> QapiEvents<S> {
     }
 }
 
+#[cfg(feature = "qapi-qmp")]
+impl<S> QapiEvents<S> {
+    /// Yields only events of type `E`, along with their timestamps
+    pub fn subscribe<E>(self) -> QmpEventSubscription<Self, E> {
+        QmpEventSubscription::new(self)
+    }
+
+    /// Yields only events of type `E` that match `predicate`, such as a specific device or job ID
+    pub fn subscribe_with<E, F: FnMut(&E) -> bool>(self, predicate: F) -> QmpEventSubscription<Self, E, F> {
+        QmpEventSubscription::with_predicate(self, predicate)
+    }
+
+    /// Shares events between multiple subscribers
+    ///
+    /// The returned broadcast future takes over routing command responses, and must be polled or spawned.
+    pub fn broadcast(self, capacity: usize, policy: QmpLagPolicy) -> (QmpEventBroadcast<Self>, QmpEventReceiver) {
+        QmpEventBroadcast::new(self, capacity, policy)
+    }
+}
+
 impl<S> Drop for QapiEvents<S> {
     fn drop(&mut self) {
         let mut commands = self.shared.commands.lock().unwrap();
         commands.pending.clear();
         commands.abandoned = true;
+        commands.wake_waiters();
     }
 }
 
-fn response_id<T>(res: &Response<T>, supports_oob: bool) -> io::Result<u32> {
-    match (res.id().and_then(|id| id.as_u64()), supports_oob) {
+fn response_id<T>(res: &Response<T>, command_ids: bool) -> io::Result<u32> {
+    match (res.id().and_then(|id| id.as_u64()), command_ids) {
         (Some(id), true) =>
             id.try_into().map_err(|e|
                 io::Error::new(io::ErrorKind::InvalidData, e)
@@ -348,46 +904,71 @@ fn response_id<T>(res: &Response<T>, supports_oob: bool) -> io::Result<u32> {
 }
 
 fn handle_response(shared: &QapiShared, res: Response<Any>) -> io::Result<()> {
-    let id = response_id(&res, shared.supports_oob)?;
+    let id = match response_id(&res, shared.command_ids) {
+        Ok(id) => id,
+        Err(e) => return shared.unknown_response(e),
+    };
 
-    if let Some(sender) = shared.command_remove(id) {
-        sender.send(res.result()).map_err(|_e|
-            io::Error::new(io::ErrorKind::InvalidData, format!("failed to send response for ID {:?}", id))
-        )
-    } else {
-        Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown QAPI response with ID {:?}", res.id())))
+    match shared.command_remove(id) {
+        Some(QapiCommandResponse::Pending(sender)) => {
+            if sender.send(res.result().map_err(From::from)).is_err() {
+                // the caller gave up between the response arriving and being delivered
+                trace!("Discarding QAPI response with ID {:?} for a cancelled command", id);
+            }
+            Ok(())
+        },
+        Some(QapiCommandResponse::Orphaned) => {
+            trace!("Discarding late QAPI response with ID {:?}", res.id());
+            Ok(())
+        },
+        None => shared.unknown_response(io::Error::new(io::ErrorKind::InvalidData, format!("unknown QAPI response with ID {:?}", res.id()))),
     }
 }
 
+/// The number of messages that [`QapiEvents`] handles in a single poll before yielding to other tasks
+const POLL_BUDGET: usize = 64;
+
 impl<M, S> Future for QapiEvents<S> where
     S: Stream<Item=io::Result<M>>,
-    M: TryInto<Response<Any>>,
+    M: QapiMessage,
 {
     type Output = io::Result<()>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = unsafe { self.get_unchecked_mut() };
-        let stream = unsafe { Pin::new_unchecked(&mut this.stream) };
+        let mut stream = unsafe { Pin::new_unchecked(&mut this.stream) };
         let shared = &this.shared;
+        #[cfg(feature = "qapi-qmp")]
+        let buffer = &mut this.buffer;
 
-        shared.poll_next(cx, |cx| Poll::Ready(Some(match futures::ready!(stream.poll_next(cx)) {
-            None => return Poll::Ready(None),
-            Some(Err(e)) => Err(e),
-            Some(Ok(res)) => match res.try_into() {
-                Ok(res) => match handle_response(shared, res) {
-                    Err(e) => Err(e),
-                    Ok(()) => {
-                        cx.waker().wake_by_ref(); // TODO: I've seen this not work with tokio?
-                        return Poll::Pending
+        shared.poll_next(cx, |cx| {
+            // drain everything that is ready, which leaves the stream to wake us once there's more
+            for _ in 0..POLL_BUDGET {
+                match futures::ready!(stream.as_mut().poll_next(cx)) {
+                    None => return Poll::Ready(None),
+                    Some(Err(e)) if shared.is_recoverable(&e) => {
+                        warn!("Failing pending QAPI commands after decode error: {}", e);
+                        shared.command_fail_all(&e);
                     },
-                },
-                Err(..) => {
-                    trace!("Ignoring QAPI event");
-                    cx.waker().wake_by_ref(); // TODO: I've seen this not work with tokio?
-                    return Poll::Pending
-                },
-            },
-        }))).map(|res| res.unwrap_or(Ok(())))
+                    Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                    Some(Ok(res)) => match res.into_response() {
+                        Ok(res) => if let Err(e) = handle_response(shared, res) {
+                            return Poll::Ready(Some(Err(e)))
+                        },
+                        #[cfg(feature = "qapi-qmp")]
+                        Err(message) => if let Some(event) = message.into_event() {
+                            buffer.push(event);
+                        },
+                        #[cfg(not(feature = "qapi-qmp"))]
+                        Err(..) => trace!("Ignoring QAPI event"),
+                    },
+                }
+            }
+
+            // still busy, so reschedule rather than starve other tasks
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }).map(|res| res.unwrap_or(Ok(())))
     }
 }
 
@@ -397,20 +978,81 @@ impl<S: Stream<Item=io::Result<QmpMessageAny>>> Stream for QapiEvents<S> {
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = unsafe { self.get_unchecked_mut() };
-        let stream = unsafe { Pin::new_unchecked(&mut this.stream) };
+        if let Some(event) = this.buffer.pop() {
+            return Poll::Ready(Some(Ok(event)))
+        }
+
+        let mut stream = unsafe { Pin::new_unchecked(&mut this.stream) };
         let shared = &this.shared;
 
-        shared.poll_next(cx, |cx| Poll::Ready(match futures::ready!(stream.poll_next(cx)) {
-            None => None, // eof
-            Some(Err(e)) => Some(Err(e)),
-            Some(Ok(QmpMessage::Event(e))) => Some(Ok(e)),
-            Some(Ok(QmpMessage::Response(res))) => match handle_response(shared, res) {
-                Err(e) => Some(Err(e)),
-                Ok(()) => {
-                    cx.waker().wake_by_ref(); // TODO: I've seen this not work with tokio?
-                    return Poll::Pending
-                },
-            },
-        }))
+        shared.poll_next(cx, |cx| {
+            for _ in 0..POLL_BUDGET {
+                match futures::ready!(stream.as_mut().poll_next(cx)) {
+                    None => return Poll::Ready(None), // eof
+                    Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                    Some(Ok(QmpMessage::Event(e))) => return Poll::Ready(Some(Ok(e))),
+                    Some(Ok(QmpMessage::Response(res))) => if let Err(e) = handle_response(shared, res) {
+                        return Poll::Ready(Some(Err(e)))
+                    },
+                }
+            }
+
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+    }
+}
+
+#[cfg(all(test, any(feature = "tokio", feature = "async-futures-io")))]
+mod test {
+    use super::*;
+
+    fn response(id: u32) -> Response<Any> {
+        serde_json::from_value(serde_json::json!({ "return": {}, "id": id })).unwrap()
+    }
+
+    #[test]
+    fn cancelled_and_unknown_responses() {
+        let shared = Arc::new(QapiShared::new(true));
+
+        // cancelled after the response arrived, but before it was delivered
+        let pending = shared.command_insert(0);
+        let sender = match shared.command_remove(0) {
+            Some(QapiCommandResponse::Pending(sender)) => sender,
+            _ => unreachable!(),
+        };
+        drop(pending);
+        shared.commands.lock().unwrap().pending.insert(0, sender);
+        handle_response(&shared, response(0)).unwrap();
+
+        // cancelled after it was sent
+        let mut pending = shared.command_insert(1);
+        pending.sent = true;
+        drop(pending);
+        handle_response(&shared, response(1)).unwrap();
+
+        handle_response(&shared, response(2)).unwrap();
+        *shared.unknown_responses.lock().unwrap() = QapiUnknownResponsePolicy::Fail;
+        assert_eq!(handle_response(&shared, response(2)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn drains_ready_messages() {
+        use futures::StreamExt;
+
+        let shared = Arc::new(QapiShared::new(true));
+        *shared.unknown_responses.lock().unwrap() = QapiUnknownResponsePolicy::Discard;
+        let read = Arc::new(AtomicUsize::new(0));
+        let counter = read.clone();
+        let stream = futures::stream::iter((0..POLL_BUDGET as u32 + 1).map(|id| Ok(response(id))))
+            .inspect(move |_| { counter.fetch_add(1, Ordering::Relaxed); })
+            .chain(futures::stream::pending());
+        let mut events = QapiEvents::new(stream, shared);
+
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        assert!(events.poll_unpin(&mut cx).is_pending());
+        assert_eq!(read.load(Ordering::Relaxed), POLL_BUDGET);
+        assert!(events.poll_unpin(&mut cx).is_pending());
+        assert_eq!(read.load(Ordering::Relaxed), POLL_BUDGET + 1);
     }
 }
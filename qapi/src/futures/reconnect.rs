@@ -0,0 +1,280 @@
+use std::sync::{Arc, Weak, Mutex};
+use std::time::Duration;
+use std::io;
+use futures::channel::{oneshot, mpsc};
+use futures::future::{self, Either};
+use futures::{Future, FutureExt, Sink, Stream, StreamExt};
+use log::{debug, warn};
+use qapi_qmp::{QapiCapabilities, QMPCapability, qmp_capabilities, Event};
+use crate::{Command, Execute, ExecuteResult};
+use super::{QapiEvents, QapiService, QmpStreamNegotiation, QmpEventReceiver, QmpLagPolicy};
+use super::broadcast::QmpEventPublisher;
+
+pub struct QmpReconnectConfig {
+    /// Capabilities to enable each time the connection is negotiated
+    pub capabilities: Vec<QMPCapability>,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Give up after this many consecutive failed connection attempts
+    pub max_attempts: Option<usize>,
+    /// Default command timeout for each new connection's service
+    pub timeout: Option<Duration>,
+}
+
+impl Default for QmpReconnectConfig {
+    fn default() -> Self {
+        Self {
+            capabilities: Vec::new(),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            max_attempts: None,
+            timeout: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum QmpReconnectEvent {
+    /// A new connection was established and negotiated
+    Connected {
+        generation: u64,
+        capabilities: QapiCapabilities,
+    },
+    /// The connection was lost, after failing any commands that were still in flight
+    Disconnected {
+        generation: u64,
+        error: Option<Arc<io::Error>>,
+    },
+    /// A connection attempt failed, and will be retried after `delay`
+    ConnectFailed {
+        attempt: usize,
+        delay: Duration,
+        error: Arc<io::Error>,
+    },
+    /// The supervisor gave up reconnecting, or all handles were dropped
+    Closed,
+}
+
+struct QmpReconnectShared<W> {
+    service: Mutex<Option<Arc<QapiService<W>>>>,
+    listeners: Mutex<Vec<mpsc::UnboundedSender<QmpReconnectEvent>>>,
+    events: QmpEventPublisher,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl<W> QmpReconnectShared<W> {
+    fn notify(&self, event: QmpReconnectEvent) {
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.retain(|l| l.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+/// A stable handle to a QMP connection that is re-established whenever it is lost
+///
+/// Commands executed while disconnected fail immediately with `NotConnected`. QMP events from
+/// every connection are delivered to the receivers created by [`events`](Self::events).
+pub struct QmpReconnectHandle<W> {
+    shared: Arc<QmpReconnectShared<W>>,
+}
+
+impl<W> Clone for QmpReconnectHandle<W> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<W: Send + Sync + 'static> QmpReconnectHandle<W> {
+    /// Spawns a supervisor task that (re)connects by calling `connect`
+    pub fn spawn<R, F, FF>(connect: F, config: QmpReconnectConfig) -> Self where
+        F: FnMut() -> FF + Send + 'static,
+        FF: Future<Output=io::Result<QmpStreamNegotiation<R, W>>> + Send + 'static,
+        QapiEvents<R>: Future<Output=io::Result<()>> + Stream<Item=io::Result<Event>> + Unpin + Send + 'static,
+        W: Sink<Execute<qmp_capabilities, u32>, Error=io::Error> + Unpin,
+        R: Send + 'static,
+    {
+        let (shutdown_sender, shutdown) = oneshot::channel();
+        let events = QmpEventPublisher::default();
+        let shared = Arc::new(QmpReconnectShared {
+            service: Mutex::new(None),
+            listeners: Mutex::new(Vec::new()),
+            events: events.clone(),
+            _shutdown: shutdown_sender,
+        });
+
+        ::tokio::spawn(supervise(Arc::downgrade(&shared), events, shutdown, connect, config));
+
+        Self {
+            shared,
+        }
+    }
+}
+
+impl<W> QmpReconnectHandle<W> {
+    /// The service for the current connection, if any
+    pub fn service(&self) -> Option<Arc<QapiService<W>>> {
+        self.shared.service.lock().unwrap().clone()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.shared.service.lock().unwrap().is_some()
+    }
+
+    /// Receives connection state changes from this point on
+    pub fn connection_events(&self) -> mpsc::UnboundedReceiver<QmpReconnectEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.shared.listeners.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Receives QMP events from this point on, across reconnections
+    ///
+    /// The receiver ends once the supervisor is closed.
+    pub fn events(&self, capacity: usize, policy: QmpLagPolicy) -> QmpEventReceiver {
+        self.shared.events.subscribe(capacity, policy)
+    }
+
+    pub fn execute<C: Command>(&self, command: C) -> impl Future<Output=ExecuteResult<C>> where
+        W: Sink<Execute<C, u32>, Error=io::Error> + Unpin
+    {
+        let execute = self.service().map(|service| service.execute(command));
+
+        async move {
+            match execute {
+                Some(execute) => execute.await,
+                None => Err(io::Error::new(io::ErrorKind::NotConnected, format!("QMP connection unavailable for {}", C::NAME)).into()),
+            }
+        }
+    }
+}
+
+async fn supervise<R, W, F, FF>(shared: Weak<QmpReconnectShared<W>>, publisher: QmpEventPublisher, shutdown: oneshot::Receiver<()>, mut connect: F, config: QmpReconnectConfig) where
+    F: FnMut() -> FF,
+    FF: Future<Output=io::Result<QmpStreamNegotiation<R, W>>>,
+    QapiEvents<R>: Future<Output=io::Result<()>> + Stream<Item=io::Result<Event>> + Unpin,
+    W: Sink<Execute<qmp_capabilities, u32>, Error=io::Error> + Unpin,
+{
+    let notify = |event| match shared.upgrade() {
+        Some(shared) => {
+            shared.notify(event);
+            true
+        },
+        None => false,
+    };
+    let mut shutdown = shutdown.fuse();
+    let mut generation = 0u64;
+
+    'connect: loop {
+        let mut attempt = 0usize;
+        let mut backoff = config.initial_backoff;
+        let (stream, capabilities) = loop {
+            let res = async {
+                let negotiation = connect().await?;
+                let capabilities = negotiation.capabilities.clone();
+                negotiation.negotiate_caps(config.capabilities.iter().copied()).await
+                    .map(|stream| (stream, capabilities))
+            };
+            futures::pin_mut!(res);
+            let res = match future::select(res, &mut shutdown).await {
+                Either::Left((res, _)) => res,
+                Either::Right(..) => break 'connect,
+            };
+
+            let error = match res {
+                Ok(res) => break res,
+                Err(e) => e,
+            };
+
+            attempt += 1;
+            debug!("QMP connection attempt {} failed: {:?}", attempt, error);
+            if config.max_attempts.map(|max| attempt >= max).unwrap_or(false) {
+                warn!("QMP reconnection abandoned after {} attempts: {:?}", attempt, error);
+                break 'connect
+            }
+
+            if !notify(QmpReconnectEvent::ConnectFailed {
+                attempt,
+                delay: backoff,
+                error: Arc::new(error),
+            }) {
+                break 'connect
+            }
+
+            let sleep = ::tokio::time::sleep(backoff);
+            futures::pin_mut!(sleep);
+            if let Either::Right(..) = future::select(sleep, &mut shutdown).await {
+                break 'connect
+            }
+            backoff = (backoff * 2).min(config.max_backoff);
+        };
+
+        generation += 1;
+        let (mut service, mut events) = stream.into_parts();
+        service.set_timeout(config.timeout);
+        match shared.upgrade() {
+            Some(shared) => {
+                *shared.service.lock().unwrap() = Some(Arc::new(service));
+                shared.notify(QmpReconnectEvent::Connected {
+                    generation,
+                    capabilities,
+                });
+            },
+            None => break,
+        }
+
+        // reading events as a stream also routes command responses
+        let res = loop {
+            match future::select(events.next(), &mut shutdown).await {
+                Either::Left((Some(Ok(event)), _)) => publisher.publish(event),
+                Either::Left((Some(Err(e)), _)) => break Err(e),
+                Either::Left((None, _)) => break Ok(()),
+                Either::Right(..) => break 'connect,
+            }
+        };
+
+        if let Some(shared) = shared.upgrade() {
+            shared.service.lock().unwrap().take();
+        }
+        // fails any commands that are still waiting on a response
+        drop(events);
+
+        if !notify(QmpReconnectEvent::Disconnected {
+            generation,
+            error: res.err().map(Arc::new),
+        }) {
+            break
+        }
+    }
+
+    publisher.close(None);
+    if let Some(shared) = shared.upgrade() {
+        shared.service.lock().unwrap().take();
+        shared.notify(QmpReconnectEvent::Closed);
+    }
+}
+
+#[cfg(all(unix, feature = "async-tokio-net"))]
+impl QmpReconnectHandle<super::QmpStreamTokio<::tokio::io::WriteHalf<::tokio::net::UnixStream>>> {
+    pub fn spawn_uds<P: AsRef<std::path::Path> + Send + Sync + 'static>(socket_addr: P, config: QmpReconnectConfig) -> Self {
+        let socket_addr = Arc::new(socket_addr);
+        Self::spawn(move || {
+            let socket_addr = socket_addr.clone();
+            async move {
+                super::QmpStreamTokio::open_uds(socket_addr.as_ref()).await
+            }
+        }, config)
+    }
+}
+
+#[cfg(feature = "async-tokio-net")]
+impl QmpReconnectHandle<super::QmpStreamTokio<::tokio::io::WriteHalf<::tokio::net::TcpStream>>> {
+    pub fn spawn_tcp<A: ::tokio::net::ToSocketAddrs + Clone + Send + Sync + 'static>(socket_addr: A, config: QmpReconnectConfig) -> Self {
+        Self::spawn(move || {
+            let socket_addr = socket_addr.clone();
+            async move {
+                super::QmpStreamTokio::open_tcp(socket_addr).await
+            }
+        }, config)
+    }
+}
@@ -0,0 +1,123 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::sync::{Mutex, atomic::{AtomicBool, Ordering}};
+#[cfg(feature = "qapi-qga")]
+use bytes::BytesMut;
+use futures::Stream;
+#[cfg(feature = "qapi-qga")]
+use futures::Sink;
+use log::{trace, warn};
+use qapi_spec::{Response, Any};
+#[cfg(feature = "qapi-qga")]
+use qapi_spec::Execute;
+use super::codec::JsonLinesCodec;
+
+pub(super) type QgaCodec = JsonLinesCodec<Response<Any>>;
+
+/// A JSON lines transport that exposes its codec and write buffer
+pub(super) trait QgaFramed {
+    fn codec(&self) -> &QgaCodec;
+    fn codec_mut(&mut self) -> &mut QgaCodec;
+    #[cfg(feature = "qapi-qga")]
+    fn write_buffer_mut(&mut self) -> &mut BytesMut;
+}
+
+/// Coordinates `guest-sync-delimited` between the read and write halves of a QGA stream
+#[derive(Default)]
+pub(super) struct QgaSyncState {
+    auto: AtomicBool,
+    // the read half must discard input until the next sentinel
+    discard: AtomicBool,
+    // the write half must resynchronise before its next command
+    needs_sync: AtomicBool,
+    // an implicit sync whose response is hidden from the service
+    expect_sync: Mutex<Option<Any>>,
+}
+
+impl QgaSyncState {
+    #[cfg(feature = "qapi-qga")]
+    pub fn set_auto_resync(&self, enable: bool) {
+        self.auto.store(enable, Ordering::Relaxed);
+        self.needs_sync.store(enable, Ordering::Relaxed);
+        self.discard.store(enable, Ordering::Relaxed);
+    }
+
+    fn is_sync_response(&self, res: &Response<Any>) -> bool {
+        let mut expect = self.expect_sync.lock().unwrap();
+        match (&*expect, res.clone().result()) {
+            (Some(id), Ok(res)) if *id == res => {
+                *expect = None;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Reads the next response, reporting and recovering from decode errors when resyncing automatically
+    pub fn poll_next<F>(&self, mut framed: Pin<&mut F>, buffered: &mut Option<Response<Any>>, cx: &mut Context) -> Poll<Option<io::Result<Response<Any>>>> where
+        F: QgaFramed + Stream<Item=io::Result<Response<Any>>>,
+    {
+        let this = unsafe { framed.as_mut().get_unchecked_mut() };
+        let codec = this.codec_mut();
+        codec.set_resync_on_error(self.auto.load(Ordering::Relaxed));
+        if self.discard.swap(false, Ordering::Relaxed) {
+            codec.resync();
+        }
+
+        loop {
+            let this = unsafe { framed.as_mut().get_unchecked_mut() };
+            if let Some(e) = this.codec_mut().take_error() {
+                warn!("QGA response could not be decoded, resynchronising: {}", e);
+                self.needs_sync.store(true, Ordering::Relaxed);
+                return Poll::Ready(Some(Err(e)))
+            }
+
+            let res = match buffered.take() {
+                Some(res) => res,
+                None => match framed.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(Ok(res))) => res,
+                    Poll::Ready(None) | Poll::Pending if framed.codec().has_error() => continue,
+                    res => return res,
+                },
+            };
+
+            if framed.codec().has_error() {
+                // delivered after the error that preceded it
+                *buffered = Some(res);
+            } else if self.is_sync_response(&res) {
+                trace!("Discarding QGA resync response");
+            } else {
+                return Poll::Ready(Some(Ok(res)))
+            }
+        }
+    }
+
+    /// Sends the command `name`, preceded by the sentinel and an implicit sync when needed
+    #[cfg(feature = "qapi-qga")]
+    pub fn start_send<F, T, I>(&self, mut framed: Pin<&mut F>, name: &str, item: T) -> io::Result<()> where
+        F: QgaFramed + Sink<T, Error=io::Error> + Sink<Execute<qapi_qga::guest_sync_delimited, I>, Error=io::Error>,
+    {
+        use bytes::BufMut;
+        use qapi_qga::guest_sync_delimited;
+        use super::codec::SENTINEL;
+
+        let delimited = name == <guest_sync_delimited as qapi_spec::Command>::NAME;
+        // an explicit sync serves just as well as an implicit one
+        let implicit = self.needs_sync.swap(false, Ordering::Relaxed) && !delimited;
+        if delimited || implicit {
+            // flushes any partial command from the agent's parser
+            unsafe { framed.as_mut().get_unchecked_mut() }.write_buffer_mut().put_u8(SENTINEL);
+            self.discard.store(true, Ordering::Relaxed);
+        }
+        if implicit {
+            let id = crate::qga_impl::sync_id();
+            *self.expect_sync.lock().unwrap() = Some(id.into());
+            framed.as_mut().start_send(Execute::<_, I>::new(guest_sync_delimited {
+                id: id.into(),
+            }, None))?;
+        }
+
+        framed.start_send(item)
+    }
+}
@@ -0,0 +1,396 @@
+use std::io;
+use futures::io::{AsyncRead, AsyncWrite, AsyncBufReadExt, AsyncWriteExt, BufReader};
+#[cfg(feature = "qapi-qmp")]
+use futures::{Stream, StreamExt};
+#[cfg(feature = "qapi-qmp")]
+use futures::future::{self, Either};
+use serde::{Serialize, Deserialize};
+use log::trace;
+use qapi_spec::{Response, Any, Error, ErrorClass};
+#[cfg(feature = "qapi-qmp")]
+use qapi_qmp::{QmpCommandHandler, QapiCapabilities, QMPCapability, Event, qmp_capabilities};
+#[cfg(feature = "qapi-qga")]
+use qapi_qga::QgaCommandHandler;
+
+/// Precedes a response to `guest-sync-delimited`, and may precede any request
+const SENTINEL: u8 = 0xff;
+
+#[derive(Deserialize)]
+struct ServerRequest {
+    #[serde(default)]
+    execute: Option<String>,
+    #[serde(default, rename = "exec-oob")]
+    exec_oob: Option<String>,
+    #[serde(default)]
+    arguments: Any,
+    #[serde(default)]
+    id: Option<Any>,
+}
+
+impl ServerRequest {
+    /// Takes the command name, and whether it was sent via `exec-oob`
+    fn take_name(&mut self) -> Result<(String, bool), Error> {
+        match (self.execute.take(), self.exec_oob.take()) {
+            (Some(name), None) => Ok((name, false)),
+            (None, Some(name)) => Ok((name, true)),
+            (Some(..), Some(..)) => Err(Error::new(ErrorClass::GenericError, "QMP input member 'exec-oob' is unexpected")),
+            (None, None) => Err(Error::new(ErrorClass::GenericError, "QMP input lacks member 'execute'")),
+        }
+    }
+}
+
+struct ServerConnection<R, W> {
+    read: BufReader<R>,
+    write: W,
+    line: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> ServerConnection<R, W> {
+    fn new(read: R, write: W) -> Self {
+        Self {
+            read: BufReader::new(read),
+            write,
+            line: Vec::new(),
+        }
+    }
+
+    /// Reads the next request, or `None` at EOF
+    ///
+    /// Cancellation is safe, as a partially read line is kept for the next call.
+    async fn read_request(&mut self) -> io::Result<Option<Result<ServerRequest, Error>>> {
+        loop {
+            self.read.read_until(b'\n', &mut self.line).await?;
+            if self.line.is_empty() {
+                return Ok(None)
+            }
+
+            trace!("<- {}", String::from_utf8_lossy(&self.line));
+            let start = self.line.iter().position(|&c| c != SENTINEL && !c.is_ascii_whitespace());
+            let res = start.map(|start| serde_json::from_slice(&self.line[start..])
+                .map_err(|e| Error::new(ErrorClass::GenericError, format!("JSON parse error, {}", e)))
+            );
+            self.line.clear();
+            if let Some(res) = res {
+                return Ok(Some(res))
+            }
+        }
+    }
+
+    async fn send<T: Serialize>(&mut self, item: &T, sentinel: bool) -> io::Result<()> {
+        let mut line = Vec::new();
+        if sentinel {
+            line.push(SENTINEL);
+        }
+        serde_json::to_writer(&mut line, item)?;
+        trace!("-> {}", String::from_utf8_lossy(&line));
+        line.push(b'\n');
+
+        self.write.write_all(&line).await?;
+        self.write.flush().await
+    }
+
+    async fn respond(&mut self, result: Result<Any, Error>, id: Option<Any>, sentinel: bool) -> io::Result<()> {
+        self.send(&Response::new(result, id), sentinel).await
+    }
+}
+
+/// Serves QMP over a single connection, dispatching negotiated commands to a handler
+#[cfg(feature = "qapi-qmp")]
+pub struct QmpServer<H> {
+    handler: H,
+    greeting: QapiCapabilities,
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<H: QmpCommandHandler> QmpServer<H> {
+    pub fn new(handler: H, greeting: QapiCapabilities) -> Self {
+        Self {
+            handler,
+            greeting,
+        }
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    pub fn into_inner(self) -> H {
+        self.handler
+    }
+
+    /// Serves a client until it disconnects
+    pub async fn serve<R, W>(&mut self, read: R, write: W) -> io::Result<()> where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        self.serve_with_events(read, write, futures::stream::empty()).await
+    }
+
+    /// Serves a client until it disconnects, sending it `events` once capabilities have been negotiated
+    pub async fn serve_with_events<R, W, E>(&mut self, read: R, write: W, events: E) -> io::Result<()> where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+        E: Stream<Item=Event>,
+    {
+        let mut conn = ServerConnection::new(read, write);
+        conn.send(&self.greeting, false).await?;
+
+        futures::pin_mut!(events);
+        let mut events_done = false;
+        let mut negotiated = false;
+        let mut oob = false;
+        loop {
+            let req = if negotiated && !events_done {
+                let next = match future::select(Box::pin(conn.read_request()), events.next()).await {
+                    Either::Left((req, _)) => Either::Left(req?),
+                    Either::Right((event, _)) => Either::Right(event),
+                };
+                match next {
+                    Either::Left(req) => req,
+                    Either::Right(Some(event)) => {
+                        conn.send(&event, false).await?;
+                        continue
+                    },
+                    Either::Right(None) => {
+                        events_done = true;
+                        continue
+                    },
+                }
+            } else {
+                conn.read_request().await?
+            };
+
+            let mut req = match req {
+                Some(Ok(req)) => req,
+                Some(Err(e)) => {
+                    conn.respond(Err(e), None, false).await?;
+                    continue
+                },
+                None => break Ok(()),
+            };
+
+            let res = match req.take_name() {
+                Err(e) => Err(e),
+                Ok((_, true)) if !oob =>
+                    Err(Error::new(ErrorClass::GenericError, "QMP input member 'exec-oob' requires capability 'oob'")),
+                Ok((name, true)) if !qapi_qmp::allows_oob(&name) =>
+                    Err(Error::new(ErrorClass::GenericError, format!("The command {} does not support OOB", name))),
+                Ok((name, _)) if name == <qmp_capabilities as qapi_spec::Command>::NAME => if negotiated {
+                    Err(Error::new(ErrorClass::CommandNotFound, "Capabilities negotiation is already complete, command ignored"))
+                } else {
+                    let supports_oob = self.greeting.supports_oob();
+                    qapi_spec::handle_command(req.arguments.take(), |c: qmp_capabilities| {
+                        let enable_oob = c.enable.unwrap_or_default().contains(&QMPCapability::oob);
+                        if enable_oob && !supports_oob {
+                            Err(Error::new(ErrorClass::GenericError, "Capability 'oob' not available"))
+                        } else {
+                            negotiated = true;
+                            oob = enable_oob;
+                            Ok(qapi_spec::Empty { })
+                        }
+                    })
+                },
+                Ok(..) if !negotiated =>
+                    Err(Error::new(ErrorClass::CommandNotFound, "Expecting capabilities negotiation with 'qmp_capabilities'")),
+                Ok((name, _)) => self.handler.dispatch(&name, req.arguments.take()),
+            };
+            conn.respond(res, req.id, false).await?;
+        }
+    }
+}
+
+/// Serves the guest agent protocol over a single connection
+#[cfg(feature = "qapi-qga")]
+pub struct QgaServer<H> {
+    handler: H,
+    answer_sync: bool,
+}
+
+#[cfg(feature = "qapi-qga")]
+impl<H: QgaCommandHandler> QgaServer<H> {
+    pub fn new(handler: H) -> Self {
+        Self {
+            handler,
+            answer_sync: true,
+        }
+    }
+
+    /// Whether `guest-sync` and `guest-sync-delimited` are answered by echoing their id, rather
+    /// than being dispatched to the handler
+    ///
+    /// This is enabled by default, so that clients can synchronise with any handler.
+    pub fn set_answer_sync(&mut self, enable: bool) {
+        self.answer_sync = enable;
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    pub fn into_inner(self) -> H {
+        self.handler
+    }
+
+    /// Serves a client until it disconnects
+    pub async fn serve<R, W>(&mut self, read: R, write: W) -> io::Result<()> where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut conn = ServerConnection::new(read, write);
+        while let Some(req) = conn.read_request().await? {
+            let (res, id, sentinel) = match req {
+                Ok(mut req) => match req.take_name() {
+                    Ok((_, true)) => (Err(Error::new(ErrorClass::GenericError, "QMP input member 'exec-oob' is unexpected")), req.id, false),
+                    Ok((name, false)) => (
+                        self.dispatch(&name, req.arguments.take()),
+                        req.id,
+                        name == <qapi_qga::guest_sync_delimited as qapi_spec::Command>::NAME,
+                    ),
+                    Err(e) => (Err(e), req.id, false),
+                },
+                Err(e) => (Err(e), None, false),
+            };
+            conn.respond(res, id, sentinel).await?;
+        }
+
+        Ok(())
+    }
+
+    fn dispatch(&mut self, name: &str, arguments: Any) -> Result<Any, Error> {
+        use qapi_qga::{guest_sync, guest_sync_delimited};
+
+        match name {
+            _ if !self.answer_sync => self.handler.dispatch(name, arguments),
+            <guest_sync as qapi_spec::Command>::NAME =>
+                qapi_spec::handle_command(arguments, |c: guest_sync| Ok(c.id)),
+            <guest_sync_delimited as qapi_spec::Command>::NAME =>
+                qapi_spec::handle_command(arguments, |c: guest_sync_delimited| Ok(c.id)),
+            name => self.handler.dispatch(name, arguments),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::io::Cursor;
+    #[cfg(feature = "qapi-qmp")]
+    use qapi_qmp::{QmpCommandHandler, QapiCapabilities, QmpCapability, QMP, VersionInfo, VersionTriple, query_version};
+    #[cfg(feature = "qapi-qmp")]
+    use super::QmpServer;
+    #[cfg(feature = "qapi-qga")]
+    use qapi_qga::{QgaCommandHandler, guest_sync};
+    #[cfg(feature = "qapi-qga")]
+    use super::{QgaServer, SENTINEL};
+
+    fn responses(output: &[u8]) -> Vec<serde_json::Value> {
+        output.split(|&c| c == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_slice(l).unwrap())
+            .collect()
+    }
+
+    #[cfg(feature = "qapi-qmp")]
+    struct Version;
+
+    #[cfg(feature = "qapi-qmp")]
+    impl QmpCommandHandler for Version {
+        fn query_version(&mut self, _: query_version) -> qapi_spec::CommandResult<query_version> {
+            Ok(VersionInfo {
+                qemu: VersionTriple {
+                    major: 8,
+                    minor: 2,
+                    micro: 0,
+                },
+                package: String::new(),
+            })
+        }
+    }
+
+    #[cfg(feature = "qapi-qmp")]
+    #[test]
+    fn negotiate_and_dispatch() {
+        let greeting = QapiCapabilities {
+            QMP: QMP {
+                version: Version.query_version(query_version { }).unwrap(),
+                capabilities: vec![QmpCapability::OutOfBand],
+            },
+        };
+        let input = concat!(
+            "{\"execute\":\"query-version\",\"id\":0}\n",
+            "{\"execute\":\"qmp_capabilities\",\"arguments\":{\"enable\":[\"oob\"]}}\n",
+            "{\"exec-oob\":\"query-version\",\"id\":1}\n",
+            "{\"exec-oob\":\"migrate-pause\",\"id\":3}\n",
+            "{\"execute\":\"query-status\",\"id\":2}\n",
+            "{\"execute\"\n",
+        );
+        let mut output = Vec::new();
+        let mut server = QmpServer::new(Version, greeting);
+        futures::executor::block_on(server.serve(Cursor::new(input), Cursor::new(&mut output))).unwrap();
+
+        let lines = responses(&output);
+        assert_eq!(lines.len(), 7);
+        assert_eq!(lines[0]["QMP"]["capabilities"][0], "oob");
+        assert_eq!(lines[1]["error"]["class"], "CommandNotFound");
+        assert_eq!(lines[1]["id"], 0);
+        assert_eq!(lines[2]["return"], serde_json::json!({ }));
+        assert_eq!(lines[3]["error"]["class"], "GenericError");
+        assert_eq!(lines[3]["error"]["desc"], "The command query-version does not support OOB");
+        assert_eq!(lines[3]["id"], 1);
+        // dispatched to the handler, which does not implement it
+        assert_eq!(lines[4]["error"]["class"], "CommandNotFound");
+        assert_eq!(lines[4]["id"], 3);
+        assert_eq!(lines[5]["error"]["class"], "CommandNotFound");
+        assert_eq!(lines[6]["error"]["class"], "GenericError");
+    }
+
+    #[cfg(feature = "qapi-qga")]
+    struct Sync;
+
+    #[cfg(feature = "qapi-qga")]
+    impl QgaCommandHandler for Sync {
+        fn guest_sync(&mut self, c: guest_sync) -> qapi_spec::CommandResult<guest_sync> {
+            Ok(c.id + 1)
+        }
+    }
+
+    #[cfg(feature = "qapi-qga")]
+    #[test]
+    fn answer_sync() {
+        let input = &[
+            &b"{\"execute\":\"guest-sync\",\"arguments\":{\"id\":5}}\n"[..],
+            &b"\xff{\"execute\":\"guest-sync-delimited\",\"arguments\":{\"id\":6}}\n"[..],
+            &b"{\"execute\":\"guest-info\"}\n"[..],
+        ].concat()[..];
+        let mut output = Vec::new();
+        let mut server = QgaServer::new(Sync);
+        futures::executor::block_on(server.serve(Cursor::new(input), Cursor::new(&mut output))).unwrap();
+
+        // only the delimited response is preceded by the sentinel
+        assert_eq!(output.iter().filter(|&&c| c == SENTINEL).count(), 1);
+        let delimited = output.iter().position(|&c| c == SENTINEL).unwrap();
+        assert_eq!(output[delimited - 1], b'\n');
+        output.remove(delimited);
+        let lines = responses(&output);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["return"], 5);
+        assert_eq!(lines[1]["return"], 6);
+        assert_eq!(lines[2]["error"]["class"], "CommandNotFound");
+
+        let mut output = Vec::new();
+        server.set_answer_sync(false);
+        futures::executor::block_on(server.serve(Cursor::new(input), Cursor::new(&mut output))).unwrap();
+
+        output.retain(|&c| c != SENTINEL);
+        let lines = responses(&output);
+        assert_eq!(lines[0]["return"], 6);
+        assert_eq!(lines[1]["error"]["class"], "CommandNotFound");
+    }
+}
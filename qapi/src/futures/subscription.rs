@@ -0,0 +1,72 @@
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::task::{Context, Poll};
+use std::pin::Pin;
+use futures::Stream;
+use qapi_qmp::Event;
+use crate::Timestamp;
+
+/// A stream of a single QMP event type, extracted from a stream of [`Event`]s
+///
+/// Events of other types, or that are rejected by the predicate, are skipped.
+#[must_use = "streams do nothing unless polled"]
+pub struct QmpEventSubscription<S, E, F = fn(&E) -> bool> {
+    stream: S,
+    predicate: F,
+    _event: PhantomData<fn() -> E>,
+}
+
+impl<S, E> QmpEventSubscription<S, E> {
+    pub fn new(stream: S) -> Self {
+        Self::with_predicate(stream, |_| true)
+    }
+}
+
+impl<S, E, F> QmpEventSubscription<S, E, F> {
+    pub fn with_predicate(stream: S, predicate: F) -> Self {
+        Self {
+            stream,
+            predicate,
+            _event: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+// the predicate is never pinned
+impl<S: Unpin, E, F> Unpin for QmpEventSubscription<S, E, F> { }
+
+impl<S, E, F, R> Stream for QmpEventSubscription<S, E, F> where
+    S: Stream<Item=Result<Event, R>>,
+    E: crate::Event + TryFrom<Event, Error=Event>,
+    F: FnMut(&E) -> bool,
+{
+    type Item = Result<(E, Timestamp), R>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut stream = unsafe { Pin::new_unchecked(&mut this.stream) };
+
+        loop {
+            match futures::ready!(stream.as_mut().poll_next(cx)) {
+                None => return Poll::Ready(None),
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Some(Ok(event)) => {
+                    if event.name() != E::NAME {
+                        continue
+                    }
+
+                    let timestamp = event.timestamp();
+                    match E::try_from(event) {
+                        Ok(data) if (this.predicate)(&data) =>
+                            return Poll::Ready(Some(Ok((data, timestamp)))),
+                        _ => (),
+                    }
+                },
+            }
+        }
+    }
+}
@@ -2,6 +2,8 @@ use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::sync::Arc;
+#[cfg(feature = "qapi-qga")]
+use std::sync::atomic::Ordering;
 use futures::Stream;
 #[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
 use futures::Sink;
@@ -9,30 +11,52 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf, split};
 use tokio_util::codec::{Framed, FramedParts};
 use qapi_spec::{Response, Any};
 #[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
-use qapi_spec::Execute;
+use qapi_spec::{Execute, ExecuteRaw};
+#[cfg(feature = "qapi-qmp")]
+use qapi_spec::ExecuteOob;
 #[cfg(feature = "qapi-qmp")]
-use qapi_qmp::{QmpMessageAny, QmpCommand, QapiCapabilities, QMPCapability};
+use qapi_qmp::{QmpMessageAny, QmpCommand, QapiCapabilities};
 #[cfg(feature = "qapi-qmp")]
 use super::QmpStreamNegotiation;
+#[cfg(feature = "qapi-qga")]
+use bytes::BytesMut;
+use super::resync::{QgaSyncState, QgaFramed, QgaCodec};
 use super::{codec::JsonLinesCodec, QapiEvents, QapiService, QapiStream, QapiShared};
 
+impl<S> QgaFramed for Framed<S, QgaCodec> {
+    fn codec(&self) -> &QgaCodec {
+        Framed::codec(self)
+    }
+
+    fn codec_mut(&mut self) -> &mut QgaCodec {
+        Framed::codec_mut(self)
+    }
+
+    #[cfg(feature = "qapi-qga")]
+    fn write_buffer_mut(&mut self) -> &mut BytesMut {
+        Framed::write_buffer_mut(self)
+    }
+}
+
 pub struct QgaStreamTokio<S> {
-    stream: Framed<S, JsonLinesCodec<Response<Any>>>
+    stream: Framed<S, QgaCodec>,
+    sync: Arc<QgaSyncState>,
+    // a response that followed a decode error, delivered after it
+    buffered: Option<Response<Any>>,
 }
 
 impl<S> QgaStreamTokio<S> {
-    fn new(stream: S) -> Self {
+    fn new(stream: S, sync: Arc<QgaSyncState>) -> Self {
         Self {
             stream: Framed::from_parts(FramedParts::new::<()>(stream, JsonLinesCodec::new())),
+            sync,
+            buffered: None,
         }
     }
 
     fn pair<W>(self, write: W) -> QapiStream<Self, W> {
         let shared = Arc::new(QapiShared::new(false));
-        let events = QapiEvents {
-            stream: self,
-            shared: shared.clone(),
-        };
+        let events = QapiEvents::new(self, shared.clone());
         let service = QapiService::new(write, shared);
         QapiStream {
             service,
@@ -41,8 +65,9 @@ impl<S> QgaStreamTokio<S> {
     }
 
     pub fn open_split<W>(read: S, write: W) -> QapiStream<Self, QgaStreamTokio<W>> {
-        let r = Self::new(read);
-        let w = QgaStreamTokio::new(write);
+        let sync = Arc::new(QgaSyncState::default());
+        let r = Self::new(read, sync.clone());
+        let w = QgaStreamTokio::new(write, sync);
 
         r.pair(w)
     }
@@ -53,10 +78,35 @@ impl<R> QgaStreamTokio<ReadHalf<R>> {
         R: AsyncRead + AsyncWrite,
     {
         let (r, w) = split(stream);
-        let r = Self::new(r);
-        let w = QgaStreamTokio::new(w);
+        Self::open_split(r, w)
+    }
+}
 
-        r.pair(w)
+#[cfg(feature = "qapi-qga")]
+impl<R, W> QapiStream<QgaStreamTokio<R>, QgaStreamTokio<W>> {
+    /// Resynchronises using `guest-sync-delimited` before the first command, and again
+    /// after any response fails to decode
+    ///
+    /// A decode error then fails only the commands that were waiting on a response,
+    /// rather than the whole stream.
+    pub fn set_auto_resync(&mut self, enable: bool) {
+        self.events.stream.sync.set_auto_resync(enable);
+        self.service.shared.resync.store(enable, Ordering::Relaxed);
+    }
+}
+
+impl<R, W> QapiStream<QgaStreamTokio<R>, QgaStreamTokio<W>> {
+    /// Treats any message longer than `max` bytes as undecodable, rather than buffering it without limit
+    pub fn set_max_line_length(&mut self, max: Option<usize>) {
+        self.events.stream.stream.codec_mut().set_max_line_length(max);
+    }
+
+    /// Logs and skips messages that cannot be decoded, rather than failing the stream
+    ///
+    /// See [`JsonLinesCodec`] for how this affects pending commands. Automatic resynchronisation
+    /// takes precedence when it is enabled.
+    pub fn set_skip_invalid_lines(&mut self, enable: bool) {
+        self.events.stream.stream.codec_mut().set_skip_invalid(enable);
     }
 }
 
@@ -78,8 +128,9 @@ impl QgaStreamTokio<ReadHalf<tokio::net::TcpStream>> {
     }
 }
 
+#[cfg(feature = "qapi-qga")]
 impl<S> QgaStreamTokio<S> {
-    fn stream(self: Pin<&mut Self>) -> Pin<&mut Framed<S, JsonLinesCodec<Response<Any>>>> {
+    fn stream(self: Pin<&mut Self>) -> Pin<&mut Framed<S, QgaCodec>> {
         unsafe {
             self.map_unchecked_mut(|this| &mut this.stream)
         }
@@ -90,7 +141,9 @@ impl<S: AsyncRead> Stream for QgaStreamTokio<S> {
     type Item = io::Result<Response<Any>>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        self.stream().poll_next(cx)
+        let this = unsafe { self.get_unchecked_mut() };
+        let stream = unsafe { Pin::new_unchecked(&mut this.stream) };
+        this.sync.poll_next(stream, &mut this.buffered, cx)
     }
 }
 
@@ -99,7 +152,8 @@ impl<S: AsyncWrite, C: qapi_qga::QgaCommand, I: serde::Serialize> Sink<Execute<C
     type Error = io::Error;
 
     fn start_send(self: Pin<&mut Self>, item: Execute<C, I>) -> Result<(), Self::Error> {
-        self.stream().start_send(item)
+        let sync = self.sync.clone();
+        sync.start_send::<_, _, I>(self.stream(), C::NAME, item)
     }
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
@@ -115,6 +169,29 @@ impl<S: AsyncWrite, C: qapi_qga::QgaCommand, I: serde::Serialize> Sink<Execute<C
     }
 }
 
+#[cfg(feature = "qapi-qga")]
+impl<S: AsyncWrite, I: serde::Serialize> Sink<ExecuteRaw<I>> for QgaStreamTokio<S> {
+    type Error = io::Error;
+
+    fn start_send(self: Pin<&mut Self>, item: ExecuteRaw<I>) -> Result<(), Self::Error> {
+        let sync = self.sync.clone();
+        let name = item.execute.clone();
+        sync.start_send::<_, _, I>(self.stream(), &name, item)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteRaw<I>>::poll_ready(self.stream(), cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteRaw<I>>::poll_flush(self.stream(), cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteRaw<I>>::poll_close(self.stream(), cx)
+    }
+}
+
 #[cfg(feature = "qapi-qmp")]
 pub struct QmpStreamTokio<S> {
     stream: Framed<S, JsonLinesCodec<QmpMessageAny>>,
@@ -129,6 +206,21 @@ impl<S> QmpStreamTokio<S> {
     }
 }
 
+#[cfg(feature = "qapi-qmp")]
+impl<R, W> QapiStream<QmpStreamTokio<R>, W> {
+    /// Treats any message longer than `max` bytes as undecodable, rather than buffering it without limit
+    pub fn set_max_line_length(&mut self, max: Option<usize>) {
+        self.events.stream.stream.codec_mut().set_max_line_length(max);
+    }
+
+    /// Logs and skips messages that cannot be decoded, rather than failing the stream
+    ///
+    /// See [`JsonLinesCodec`] for how this affects pending commands.
+    pub fn set_skip_invalid_lines(&mut self, enable: bool) {
+        self.events.stream.stream.codec_mut().set_skip_invalid(enable);
+    }
+}
+
 #[cfg(feature = "qapi-qmp")]
 impl<S: AsyncRead> Stream for QmpStreamTokio<S> {
     type Item = io::Result<QmpMessageAny>;
@@ -159,6 +251,48 @@ impl<S: AsyncWrite, C: QmpCommand, I: serde::Serialize> Sink<Execute<C, I>> for
     }
 }
 
+#[cfg(feature = "qapi-qmp")]
+impl<S: AsyncWrite, C: QmpCommand, I: serde::Serialize> Sink<ExecuteOob<C, I>> for QmpStreamTokio<S> {
+    type Error = io::Error;
+
+    fn start_send(self: Pin<&mut Self>, item: ExecuteOob<C, I>) -> Result<(), Self::Error> {
+        self.stream().start_send(item)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteOob<C, I>>::poll_ready(self.stream(), cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteOob<C, I>>::poll_flush(self.stream(), cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteOob<C, I>>::poll_close(self.stream(), cx)
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl<S: AsyncWrite, I: serde::Serialize> Sink<ExecuteRaw<I>> for QmpStreamTokio<S> {
+    type Error = io::Error;
+
+    fn start_send(self: Pin<&mut Self>, item: ExecuteRaw<I>) -> Result<(), Self::Error> {
+        self.stream().start_send(item)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteRaw<I>>::poll_ready(self.stream(), cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteRaw<I>>::poll_flush(self.stream(), cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Sink::<ExecuteRaw<I>>::poll_close(self.stream(), cx)
+    }
+}
+
 #[cfg(feature = "qapi-qmp")]
 impl<S> QmpStreamTokio<S> {
     pub fn new(stream: S) -> Self {
@@ -183,12 +317,8 @@ impl<S> QmpStreamTokio<S> {
         read.read_buf = lines.read_buf;
         let stream = Framed::from_parts(read);
 
-        let supports_oob = capabilities.capabilities().any(|c| c == QMPCapability::oob);
-        let shared = Arc::new(QapiShared::new(supports_oob));
-        let events = QapiEvents {
-            stream: Self { stream },
-            shared: shared.clone(),
-        };
+        let shared = Arc::new(QapiShared::new(true));
+        let events = QapiEvents::new(Self { stream }, shared.clone());
         let service = QapiService::new(QmpStreamTokio::new(write), shared);
 
         Ok(QmpStreamNegotiation {
@@ -1,19 +1,25 @@
-use std::io;
+use std::{io, fmt};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use futures::{Sink, Future, FutureExt};
+use futures::future::BoxFuture;
+use futures::lock::{OwnedMutexGuard, OwnedMutexLockFuture};
 use tower_service::Service;
-use crate::{Command, Execute, ExecuteError};
-use super::QapiService;
+use crate::{Any, Command, Execute, ExecuteRaw, ExecuteError};
+use super::{QapiService, QapiPendingCommand};
 
-// this really doesn't work well for lifetime reasons?
+/// Commands in flight before [`QapiTowerService`] applies backpressure, matching the
+/// request queue length of QEMU's monitor
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 8;
 
+/// Executes commands without backpressure; prefer [`QapiTowerService`]
 impl<W: 'static, C: Command + 'static> Service<C> for QapiService<W> where
     W: Sink<Execute<C, u32>, Error=io::Error> + Unpin + Send,
 {
     type Response = C::Ok;
     type Error = ExecuteError;
-    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + 'static>>;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
@@ -23,3 +29,403 @@ impl<W: 'static, C: Command + 'static> Service<C> for QapiService<W> where
         self.execute(req).boxed()
     }
 }
+
+/// A place among the commands in flight, reserved by `poll_ready` for the next call
+enum Reservation {
+    Command(QapiPendingCommand),
+    /// Guest agent commands carry no ID, and are serialised by the write half instead
+    Unordered,
+}
+
+impl Reservation {
+    fn into_pending(self) -> Option<QapiPendingCommand> {
+        match self {
+            Reservation::Command(pending) => Some(pending),
+            Reservation::Unordered => None,
+        }
+    }
+}
+
+/// A cloneable tower service over a shared [`QapiService`]
+///
+/// `poll_ready` reserves the next command ID and a place among the commands in flight, waiting
+/// while too many are outstanding, and then locks the write half. A service that is ready holds
+/// the write half until it is called or dropped, so other callers wait on it in `poll_ready`.
+/// Guest agent commands carry no ID, so `poll_ready` waits until no command is in flight, and the
+/// write half remains locked until each response arrives. Commands whose callers gave up on them
+/// do not count towards the limit, so a response that never arrives can't stall the service.
+pub struct QapiTowerService<W> {
+    service: Arc<QapiService<W>>,
+    max_in_flight: usize,
+    // taken before waiting for a slot, so that no other caller can reuse it
+    id: Option<u32>,
+    reservation: Option<Reservation>,
+    lock: Option<OwnedMutexLockFuture<W>>,
+    write: Option<OwnedMutexGuard<W>>,
+}
+
+impl<W> QapiTowerService<W> {
+    pub fn new(service: Arc<QapiService<W>>) -> Self {
+        Self {
+            service,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            id: None,
+            reservation: None,
+            lock: None,
+            write: None,
+        }
+    }
+
+    pub fn with_max_in_flight(self, max_in_flight: usize) -> Self {
+        assert!(max_in_flight > 0, "QAPI commands in flight must be non-zero");
+
+        Self {
+            max_in_flight,
+            .. self
+        }
+    }
+
+    pub fn get_ref(&self) -> &Arc<QapiService<W>> {
+        &self.service
+    }
+
+    fn poll_reserve(&mut self, cx: &mut Context) -> Poll<Result<(), ExecuteError>> {
+        if self.reservation.is_none() {
+            futures::ready!(self.poll_slot(cx));
+        }
+        if self.write.is_none() {
+            let write = &self.service.write;
+            let lock = self.lock.get_or_insert_with(|| write.clone().lock_owned());
+            self.write = Some(futures::ready!(Pin::new(lock).poll(cx)));
+            self.lock = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_slot(&mut self, cx: &mut Context) -> Poll<()> {
+        let service = &self.service;
+        let reservation = if service.shared.command_ids {
+            let id = *self.id.get_or_insert_with(|| service.next_id());
+            match futures::ready!(service.shared.poll_command_slot(cx, Some(id), self.max_in_flight)) {
+                Some(pending) => Reservation::Command(pending),
+                // the stream is gone, so the call fails once it is executed
+                None => Reservation::Unordered,
+            }
+        } else {
+            futures::ready!(service.shared.poll_command_slot(cx, None, 1));
+            Reservation::Unordered
+        };
+        self.id = None;
+        self.reservation = Some(reservation);
+        Poll::Ready(())
+    }
+
+    fn take_reservation(&mut self) -> (Option<QapiPendingCommand>, OwnedMutexGuard<W>) {
+        match (self.reservation.take(), self.write.take()) {
+            (Some(reservation), Some(write)) => (reservation.into_pending(), write),
+            _ => panic!("QapiTowerService called before poll_ready"),
+        }
+    }
+}
+
+impl<W> Clone for QapiTowerService<W> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+            max_in_flight: self.max_in_flight,
+            id: None,
+            reservation: None,
+            lock: None,
+            write: None,
+        }
+    }
+}
+
+impl<W> From<QapiService<W>> for QapiTowerService<W> {
+    fn from(service: QapiService<W>) -> Self {
+        Self::new(service.into())
+    }
+}
+
+impl<W: 'static, C: Command + 'static> Service<C> for QapiTowerService<W> where
+    W: Sink<Execute<C, u32>, Error=io::Error> + Unpin + Send,
+{
+    type Response = C::Ok;
+    type Error = ExecuteError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.poll_reserve(cx)
+    }
+
+    fn call(&mut self, req: C) -> Self::Future {
+        let (reserved, write) = self.take_reservation();
+        let service = &self.service;
+        service.execute_locked(futures::future::ready(write), reserved, C::NAME.into(), |id| Execute::new(req, id), service.default_deadline())
+            .map(QapiService::<W>::command_decode::<C>)
+            .boxed()
+    }
+}
+
+trait ErasedCommand<W>: Send + Sync {
+    fn name(&self) -> &str;
+    fn execute(&self, service: &QapiService<W>, reserved: Option<QapiPendingCommand>, write: OwnedMutexGuard<W>) -> BoxFuture<'static, Result<Any, ExecuteError>>;
+}
+
+impl<W: 'static, C: Command + Clone + 'static> ErasedCommand<W> for C where
+    W: Sink<Execute<C, u32>, Error=io::Error> + Unpin + Send,
+{
+    fn name(&self) -> &str {
+        C::NAME
+    }
+
+    fn execute(&self, service: &QapiService<W>, reserved: Option<QapiPendingCommand>, write: OwnedMutexGuard<W>) -> BoxFuture<'static, Result<Any, ExecuteError>> {
+        let command = self.clone();
+        service.execute_locked(futures::future::ready(write), reserved, C::NAME.into(), move |id| Execute::new(command, id), service.default_deadline())
+            .boxed()
+    }
+}
+
+struct RawCommand {
+    name: String,
+    arguments: Any,
+}
+
+impl<W: 'static> ErasedCommand<W> for RawCommand where
+    W: Sink<ExecuteRaw<u32>, Error=io::Error> + Unpin + Send,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn execute(&self, service: &QapiService<W>, reserved: Option<QapiPendingCommand>, write: OwnedMutexGuard<W>) -> BoxFuture<'static, Result<Any, ExecuteError>> {
+        let (name, arguments) = (self.name.clone(), self.arguments.clone());
+        service.execute_locked(futures::future::ready(write), reserved, name.clone().into(), move |id| ExecuteRaw::new(name, arguments, id), service.default_deadline())
+            .boxed()
+    }
+}
+
+/// A command of any type, so that a single service can accept a mix of commands
+///
+/// The response is the command's undecoded return value.
+pub struct QapiRequest<W> {
+    command: Arc<dyn ErasedCommand<W>>,
+}
+
+impl<W: 'static> QapiRequest<W> {
+    pub fn new<C: Command + Clone + 'static>(command: C) -> Self where
+        W: Sink<Execute<C, u32>, Error=io::Error> + Unpin + Send,
+    {
+        Self {
+            command: Arc::new(command),
+        }
+    }
+
+    /// Executes a command by name, as with [`QapiService::execute_raw`]
+    pub fn raw<N: Into<String>>(name: N, arguments: Any) -> Self where
+        W: Sink<ExecuteRaw<u32>, Error=io::Error> + Unpin + Send,
+    {
+        Self {
+            command: Arc::new(RawCommand {
+                name: name.into(),
+                arguments,
+            }),
+        }
+    }
+}
+
+impl<W> QapiRequest<W> {
+    pub fn name(&self) -> &str {
+        self.command.name()
+    }
+}
+
+impl<W> Clone for QapiRequest<W> {
+    fn clone(&self) -> Self {
+        Self {
+            command: self.command.clone(),
+        }
+    }
+}
+
+impl<W> fmt::Debug for QapiRequest<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("QapiRequest")
+            .field(&self.name())
+            .finish()
+    }
+}
+
+impl<W> Service<QapiRequest<W>> for QapiTowerService<W> {
+    type Response = Any;
+    type Error = ExecuteError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.poll_reserve(cx)
+    }
+
+    fn call(&mut self, req: QapiRequest<W>) -> Self::Future {
+        let (reserved, write) = self.take_reservation();
+        req.command.execute(&self.service, reserved, write)
+    }
+}
+
+#[cfg(all(test, any(feature = "tokio", feature = "async-futures-io")))]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use futures::task::{ArcWake, waker};
+    use super::*;
+    use super::super::QapiShared;
+
+    /// Accepts every command without answering it
+    struct Unanswered;
+
+    impl<T> Sink<T> for Unanswered {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, _: T) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[derive(Default)]
+    struct Wakes(AtomicUsize);
+
+    impl ArcWake for Wakes {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    type Tower = QapiTowerService<Unanswered>;
+
+    fn tower(command_ids: bool, max_in_flight: usize) -> Tower {
+        let shared = Arc::new(QapiShared::new(command_ids));
+        QapiTowerService::from(QapiService::new(Unanswered, shared))
+            .with_max_in_flight(max_in_flight)
+    }
+
+    fn ready(tower: &mut Tower, cx: &mut Context) -> bool {
+        Service::<QapiRequest<Unanswered>>::poll_ready(tower, cx).is_ready()
+    }
+
+    fn call(tower: &mut Tower) -> BoxFuture<'static, Result<Any, ExecuteError>> {
+        tower.call(QapiRequest::raw("test", Any::Null))
+    }
+
+    fn reserved_id(tower: &Tower) -> Option<u32> {
+        match &tower.reservation {
+            Some(Reservation::Command(pending)) => Some(pending.id),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn in_flight_limit() {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let mut a = tower(true, 2);
+        let (mut b, mut c) = (a.clone(), a.clone());
+
+        assert!(ready(&mut a, &mut cx));
+        let mut sent = call(&mut a);
+        assert!(sent.poll_unpin(&mut cx).is_pending());
+        assert!(ready(&mut b, &mut cx));
+        let unsent = call(&mut b);
+        assert!(!ready(&mut c, &mut cx));
+
+        // a command that was never sent releases its place
+        drop(unsent);
+        assert!(ready(&mut c, &mut cx));
+        let mut called = call(&mut c);
+        assert!(called.poll_unpin(&mut cx).is_pending());
+        assert!(!ready(&mut b, &mut cx));
+
+        // nor does a command whose caller gave up on it hold its place
+        drop(sent);
+        assert!(ready(&mut b, &mut cx));
+        assert_eq!(a.service.shared.commands.lock().unwrap().orphaned.len(), 1);
+    }
+
+    #[test]
+    fn orphan_without_ids() {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let mut a = tower(false, DEFAULT_MAX_IN_FLIGHT);
+        let mut b = a.clone();
+
+        assert!(ready(&mut a, &mut cx));
+        let mut sent = call(&mut a);
+        assert!(sent.poll_unpin(&mut cx).is_pending());
+        assert!(!ready(&mut b, &mut cx));
+
+        // the response may never arrive, so it must not stall the service
+        drop(sent);
+        assert!(ready(&mut b, &mut cx));
+    }
+
+    #[test]
+    fn waiter_registered_once() {
+        let wakes = Arc::new(Wakes::default());
+        let waker = waker(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut a = tower(true, 1);
+        let mut b = a.clone();
+
+        assert!(ready(&mut a, &mut cx));
+        let unsent = call(&mut a);
+        for _ in 0..3 {
+            assert!(!ready(&mut b, &mut cx));
+        }
+        assert_eq!(a.service.shared.commands.lock().unwrap().waiters.len(), 1);
+
+        drop(unsent);
+        assert_eq!(wakes.0.load(Ordering::Relaxed), 1);
+        assert!(ready(&mut b, &mut cx));
+    }
+
+    #[test]
+    fn reservation() {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let mut a = tower(true, 1);
+        let mut b = a.clone();
+
+        // a ready service holds its id, its place and the write half
+        assert!(ready(&mut a, &mut cx));
+        let id = reserved_id(&a).unwrap();
+        assert!(a.service.shared.commands.lock().unwrap().pending.contains_key(&id));
+        assert!(a.service.write.try_lock().is_none());
+
+        // a waiting service keeps the id it took, so that no other caller can reuse it
+        assert!(!ready(&mut b, &mut cx));
+        let waiting = b.id.unwrap();
+        assert_ne!(waiting, id);
+        assert!(!ready(&mut b, &mut cx));
+        assert_eq!(b.id, Some(waiting));
+
+        // dropping a ready service releases everything it reserved
+        drop(a);
+        assert!(ready(&mut b, &mut cx));
+        assert_eq!(reserved_id(&b), Some(waiting));
+        assert_eq!(b.id, None);
+
+        // the write half is released once the command is sent
+        let mut c = b.clone().with_max_in_flight(2);
+        assert!(!ready(&mut c, &mut cx));
+        let mut sent = call(&mut b);
+        assert!(!ready(&mut c, &mut cx));
+        assert!(sent.poll_unpin(&mut cx).is_pending());
+        assert!(ready(&mut c, &mut cx));
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write, SeekFrom};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::warn;
+use qapi_qga::{
+    GuestCommand, GuestExecOutput, GuestExecStatus, guest_exec, guest_exec_status,
+    guest_file_open, guest_file_close, guest_file_read,
+};
+use crate::guest_file::{guest_file_seek, GUEST_FILE_CHUNK_SIZE};
+use crate::{Qga, ExecuteError};
+
+/// The delay before the next `guest-exec-status` poll, or an error once the command's timeout has elapsed
+pub(crate) fn poll_delay(command: &GuestCommand, pid: i64, polls: u32, elapsed: Duration) -> Result<Duration, ExecuteError> {
+    if polls == 0 {
+        return Ok(Duration::from_secs(0))
+    }
+
+    let delay = command.poll_delay(polls - 1);
+    match command.get_timeout() {
+        None => Ok(delay),
+        Some(timeout) => match timeout.checked_sub(elapsed) {
+            Some(remaining) if remaining > Duration::from_secs(0) => Ok(delay.min(remaining)),
+            _ => Err(io::Error::new(io::ErrorKind::TimedOut, format!("guest process {} did not exit within {:?}", pid, timeout)).into()),
+        },
+    }
+}
+
+/// Where a streamed process's stdout and stderr are redirected to within the guest
+pub(crate) fn output_paths() -> [String; 2] {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|t| t.as_nanos()).unwrap_or_default();
+    let name = format!("/tmp/qapi-exec-{}-{}-{}", std::process::id(), nanos, COUNTER.fetch_add(1, Ordering::Relaxed));
+    [format!("{}.out", name), format!("{}.err", name)]
+}
+
+/// Wraps data read from each of [`output_paths`]
+pub(crate) const OUTPUTS: [fn(Vec<u8>) -> GuestExecOutput; 2] = [GuestExecOutput::Stdout, GuestExecOutput::Stderr];
+
+pub(crate) fn output_open(path: &str) -> guest_file_open {
+    guest_file_open {
+        path: path.into(),
+        mode: Some("w+".into()),
+    }
+}
+
+pub(crate) fn output_read(handle: i64) -> guest_file_read {
+    guest_file_read {
+        handle,
+        count: Some(GUEST_FILE_CHUNK_SIZE as i64),
+    }
+}
+
+/// Clears the end-of-file indicator left by an earlier read, so that the file can be followed
+pub(crate) fn output_rewind(handle: i64) -> io::Result<qapi_qga::guest_file_seek> {
+    guest_file_seek(handle, SeekFrom::Current(0))
+}
+
+/// Removes the files at [`output_paths`] without waiting for it to happen
+pub(crate) fn output_remove(paths: &[String; 2]) -> guest_exec {
+    guest_exec {
+        path: "/bin/rm".into(),
+        arg: Some(vec!["-f".into(), paths[0].clone(), paths[1].clone()]),
+        env: None,
+        input_data: None,
+        capture_output: None,
+    }
+}
+
+/// Output from a guest process started by [`Qga::exec_stream`]
+///
+/// Ends after [`GuestExecOutput::Exited`], or after the first error. The output files are closed
+/// and removed once the process exits, or when this is dropped.
+pub struct QgaExecStream<'a, S: BufRead + Write> {
+    qga: &'a mut Qga<S>,
+    command: GuestCommand,
+    pid: i64,
+    started: Instant,
+    polls: u32,
+    paths: [String; 2],
+    handles: [Option<i64>; 2],
+    eof: [bool; 2],
+    pending: VecDeque<GuestExecOutput>,
+    done: bool,
+}
+
+impl<'a, S: BufRead + Write> QgaExecStream<'a, S> {
+    /// The process ID within the guest
+    pub fn pid(&self) -> i64 {
+        self.pid
+    }
+
+    fn read_outputs(&mut self) -> Result<(), ExecuteError> {
+        for (i, &handle) in self.handles.iter().enumerate() {
+            let handle = match handle {
+                Some(handle) => handle,
+                None => continue,
+            };
+            if self.eof[i] {
+                self.qga.execute(&output_rewind(handle)?)?;
+            }
+            loop {
+                let read = self.qga.execute(&output_read(handle))?;
+                self.eof[i] = read.eof;
+                let empty = read.buf_b64.is_empty();
+                if !empty {
+                    self.pending.push_back(OUTPUTS[i](read.buf_b64));
+                }
+                if read.eof || empty {
+                    break
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<(), ExecuteError> {
+        let delay = poll_delay(&self.command, self.pid, self.polls, self.started.elapsed())?;
+        std::thread::sleep(delay);
+        self.polls += 1;
+
+        // the status is checked first, so that all output has been written if it has exited
+        let status = self.qga.execute(&guest_exec_status { pid: self.pid })?;
+        self.read_outputs()?;
+        if status.exited {
+            self.done = true;
+            self.pending.extend(status.into_outputs());
+            self.cleanup();
+        }
+        Ok(())
+    }
+
+    fn cleanup(&mut self) {
+        cleanup(self.qga, &self.paths, &mut self.handles)
+    }
+}
+
+fn cleanup<S: BufRead + Write>(qga: &mut Qga<S>, paths: &[String; 2], handles: &mut [Option<i64>; 2]) {
+    if handles.iter().all(Option::is_none) {
+        return
+    }
+    for handle in handles.iter_mut().filter_map(Option::take) {
+        if let Err(e) = qga.execute(&guest_file_close { handle }) {
+            warn!("failed to close guest file handle {}: {}", handle, e);
+        }
+    }
+    if let Err(e) = qga.execute(&output_remove(paths)) {
+        warn!("failed to remove guest process output {:?}: {}", paths, e);
+    }
+}
+
+impl<'a, S: BufRead + Write> Iterator for QgaExecStream<'a, S> {
+    type Item = Result<GuestExecOutput, ExecuteError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(output) = self.pending.pop_front() {
+                return Some(Ok(output))
+            }
+            if self.done {
+                return None
+            }
+
+            if let Err(e) = self.poll() {
+                self.done = true;
+                self.cleanup();
+                return Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<'a, S: BufRead + Write> Drop for QgaExecStream<'a, S> {
+    fn drop(&mut self) {
+        self.cleanup()
+    }
+}
+
+impl<S: BufRead + Write> Qga<S> {
+    /// Starts a guest process, and streams its output as it is written until it exits
+    ///
+    /// The process is run by `/bin/sh` with its output redirected to files under `/tmp`, which are
+    /// read as it runs, so this requires a POSIX guest. Use [`exec`](Self::exec) for other guests.
+    pub fn exec_stream(&mut self, command: &GuestCommand) -> Result<QgaExecStream<'_, S>, ExecuteError> {
+        let paths = output_paths();
+        let mut handles = [None, None];
+        let mut start = || -> Result<i64, ExecuteError> {
+            for (handle, path) in handles.iter_mut().zip(&paths) {
+                *handle = Some(self.execute(&output_open(path))?);
+            }
+            self.execute(&command.to_redirected_command(&paths[0], &paths[1]))
+                .map(|exec| exec.pid)
+        };
+        let pid = match start() {
+            Ok(pid) => pid,
+            Err(e) => {
+                cleanup(self, &paths, &mut handles);
+                return Err(e)
+            },
+        };
+
+        Ok(QgaExecStream {
+            qga: self,
+            command: command.clone(),
+            pid,
+            started: Instant::now(),
+            polls: 0,
+            paths,
+            handles,
+            eof: [false; 2],
+            pending: VecDeque::new(),
+            done: false,
+        })
+    }
+
+    /// Runs a guest process to completion, returning its exit status and captured output
+    ///
+    /// Use [`GuestExecStatus::result`] to treat a failed process as an error.
+    pub fn exec(&mut self, command: &GuestCommand) -> Result<GuestExecStatus, ExecuteError> {
+        let pid = self.execute(&command.to_command())?.pid;
+        let started = Instant::now();
+        let mut polls = 0;
+        loop {
+            std::thread::sleep(poll_delay(command, pid, polls, started.elapsed())?);
+            polls += 1;
+
+            let status = self.execute(&guest_exec_status { pid })?;
+            if status.exited {
+                return Ok(status)
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix, feature = "async"))]
+pub(crate) mod test {
+    use std::collections::HashMap;
+    use std::io::BufReader;
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use qapi_qga::{
+        QgaCommandHandler, GuestCommand, GuestExec, GuestExecOutput, GuestExecStatus, GuestFileRead, GuestFileSeek,
+        guest_exec, guest_exec_status, guest_file_open, guest_file_close, guest_file_read, guest_file_seek,
+    };
+    use qapi_spec::{CommandResult, Empty};
+    use crate::futures::QgaServer;
+    use crate::{Qga, Stream};
+
+    #[derive(Default)]
+    pub(crate) struct FakeGuestState {
+        pub files: HashMap<String, Vec<u8>>,
+        pub handles: HashMap<i64, (String, usize)>,
+        outputs: Vec<String>,
+        polls: u32,
+        pub exited: bool,
+    }
+
+    /// A guest whose only process writes to stdout, then stderr, then exits over successive polls
+    #[derive(Clone, Default)]
+    pub(crate) struct FakeGuest(pub Arc<Mutex<FakeGuestState>>);
+
+    impl QgaCommandHandler for FakeGuest {
+        fn guest_exec(&mut self, c: guest_exec) -> CommandResult<guest_exec> {
+            let mut state = self.0.lock().unwrap();
+            let arg = c.arg.unwrap_or_default();
+            match &c.path[..] {
+                "/bin/sh" => {
+                    assert!(c.capture_output.is_none());
+                    assert_eq!(arg[4..], ["echo", "hello"]);
+                    state.outputs = arg[2..4].to_vec();
+                    Ok(GuestExec { pid: 1 })
+                },
+                "/bin/rm" => {
+                    for path in &arg[1..] {
+                        state.files.remove(path);
+                    }
+                    Ok(GuestExec { pid: 2 })
+                },
+                path => panic!("unexpected guest-exec {}", path),
+            }
+        }
+
+        fn guest_exec_status(&mut self, _: guest_exec_status) -> CommandResult<guest_exec_status> {
+            let mut state = self.0.lock().unwrap();
+            state.polls += 1;
+            let written = match state.polls {
+                1 => Some((0, &b"hello\n"[..])),
+                2 => Some((1, &b"oops"[..])),
+                _ => None,
+            };
+            if let Some((i, data)) = written {
+                let path = state.outputs[i].clone();
+                state.files.get_mut(&path).unwrap().extend_from_slice(data);
+            }
+            state.exited = state.polls > 2;
+            Ok(GuestExecStatus {
+                exited: state.exited,
+                exitcode: if state.exited { Some(0) } else { None },
+                signal: None,
+                out_data: None,
+                err_data: None,
+                out_truncated: None,
+                err_truncated: None,
+            })
+        }
+
+        fn guest_file_open(&mut self, c: guest_file_open) -> CommandResult<guest_file_open> {
+            let mut state = self.0.lock().unwrap();
+            assert_eq!(c.mode.as_deref(), Some("w+"));
+            state.files.insert(c.path.clone(), Vec::new());
+            let handle = state.handles.len() as i64 + 1000;
+            state.handles.insert(handle, (c.path, 0));
+            Ok(handle)
+        }
+
+        fn guest_file_read(&mut self, c: guest_file_read) -> CommandResult<guest_file_read> {
+            let mut state = self.0.lock().unwrap();
+            let (path, pos) = state.handles[&c.handle].clone();
+            let data = &state.files[&path][pos..];
+            let data = data[..data.len().min(c.count.unwrap() as usize)].to_vec();
+            let pos = pos + data.len();
+            let eof = pos == state.files[&path].len();
+            state.handles.get_mut(&c.handle).unwrap().1 = pos;
+            Ok(GuestFileRead {
+                count: data.len() as i64,
+                buf_b64: data,
+                eof,
+            })
+        }
+
+        fn guest_file_seek(&mut self, c: guest_file_seek) -> CommandResult<guest_file_seek> {
+            let state = self.0.lock().unwrap();
+            assert_eq!(c.offset, 0);
+            Ok(GuestFileSeek {
+                position: state.handles[&c.handle].1 as i64,
+                eof: false,
+            })
+        }
+
+        fn guest_file_close(&mut self, c: guest_file_close) -> CommandResult<guest_file_close> {
+            self.0.lock().unwrap().handles.remove(&c.handle);
+            Ok(Empty { })
+        }
+    }
+
+    pub(crate) fn command() -> GuestCommand {
+        let mut command = GuestCommand::new("echo");
+        command.arg("hello")
+            .poll_interval(Duration::from_millis(1), Duration::from_millis(1));
+        command
+    }
+
+    /// Serves `guest` over a socket from another thread
+    pub(crate) fn serve(guest: &FakeGuest) -> (UnixStream, thread::JoinHandle<()>) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut server_guest = QgaServer::new(guest.clone());
+        let thread = thread::spawn(move || {
+            let read = futures::io::AllowStdIo::new(server.try_clone().unwrap());
+            futures::executor::block_on(server_guest.serve(read, futures::io::AllowStdIo::new(server))).unwrap();
+        });
+        (client, thread)
+    }
+
+    #[test]
+    fn exec_stream() {
+        let guest = FakeGuest::default();
+        let (client, server) = serve(&guest);
+        let mut qga = Qga::new(Stream::new(BufReader::new(client.try_clone().unwrap()), client));
+
+        let mut stream = qga.exec_stream(&command()).unwrap();
+        match stream.next().unwrap().unwrap() {
+            GuestExecOutput::Stdout(data) => assert_eq!(data, b"hello\n"),
+            output => panic!("unexpected {:?}", output),
+        }
+        // the output arrived while the process was still running
+        assert!(!guest.0.lock().unwrap().exited);
+
+        let outputs = stream.collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(matches!(&outputs[..], [GuestExecOutput::Stderr(data), GuestExecOutput::Exited(status)]
+            if data == b"oops" && status.exitcode == Some(0)
+        ));
+
+        drop(qga);
+        server.join().unwrap();
+        let state = guest.0.lock().unwrap();
+        assert!(state.files.is_empty());
+        assert!(state.handles.is_empty());
+    }
+}
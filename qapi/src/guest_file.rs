@@ -0,0 +1,156 @@
+use std::io::{self, BufRead, Read, Write, Seek, SeekFrom};
+use std::convert::TryFrom;
+use log::warn;
+use qapi_qga::{
+    guest_file_open, guest_file_close, guest_file_read, guest_file_write, guest_file_seek, guest_file_flush,
+    GuestFileWhence, QGASeek,
+};
+use crate::{Qga, ExecuteError};
+
+/// The largest amount of data transferred by a single `guest-file-read` or `guest-file-write`
+pub const GUEST_FILE_CHUNK_SIZE: usize = 0x10000;
+
+pub(crate) fn guest_file_seek(handle: i64, pos: SeekFrom) -> io::Result<guest_file_seek> {
+    let (offset, whence) = match pos {
+        SeekFrom::Start(offset) => (i64::try_from(offset)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "seek offset out of range"))?, QGASeek::set),
+        SeekFrom::Current(offset) => (offset, QGASeek::cur),
+        SeekFrom::End(offset) => (offset, QGASeek::end),
+    };
+    Ok(guest_file_seek {
+        handle,
+        offset,
+        whence: GuestFileWhence::name(whence),
+    })
+}
+
+/// A file opened within the guest by `guest-file-open`
+///
+/// The handle is closed on drop, though [`close`](Self::close) should be preferred in order to
+/// observe any error.
+pub struct GuestFile<'a, S: BufRead + Write> {
+    qga: &'a mut Qga<S>,
+    handle: i64,
+    chunk_size: usize,
+    closed: bool,
+}
+
+impl<'a, S: BufRead + Write> GuestFile<'a, S> {
+    /// Opens `path` with an `fopen()` style `mode`, such as `r` or `w`
+    pub fn open(qga: &'a mut Qga<S>, path: &str, mode: &str) -> Result<Self, ExecuteError> {
+        let handle = qga.execute(&guest_file_open {
+            path: path.into(),
+            mode: Some(mode.into()),
+        })?;
+        Ok(GuestFile {
+            qga,
+            handle,
+            chunk_size: GUEST_FILE_CHUNK_SIZE,
+            closed: false,
+        })
+    }
+
+    pub fn handle(&self) -> i64 {
+        self.handle
+    }
+
+    /// Limits the size of each transfer, which defaults to [`GUEST_FILE_CHUNK_SIZE`]
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size.max(1);
+    }
+
+    pub fn close(mut self) -> Result<(), ExecuteError> {
+        self.closed = true;
+        self.qga.execute(&guest_file_close { handle: self.handle })
+            .map(drop)
+    }
+}
+
+impl<'a, S: BufRead + Write> Read for GuestFile<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = buf.len().min(self.chunk_size);
+        if count == 0 {
+            return Ok(0)
+        }
+
+        let read = self.qga.execute(&guest_file_read {
+            handle: self.handle,
+            count: Some(count as i64),
+        })?;
+        let data = &read.buf_b64[..read.buf_b64.len().min(count)];
+        buf[..data.len()].copy_from_slice(data);
+        Ok(data.len())
+    }
+}
+
+impl<'a, S: BufRead + Write> Write for GuestFile<'a, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = buf.len().min(self.chunk_size);
+        if count == 0 {
+            return Ok(0)
+        }
+
+        let written = self.qga.execute(&guest_file_write {
+            handle: self.handle,
+            buf_b64: buf[..count].to_owned(),
+            count: None,
+        })?;
+        Ok(written.count as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.qga.execute(&guest_file_flush { handle: self.handle })
+            .map(drop).map_err(From::from)
+    }
+}
+
+impl<'a, S: BufRead + Write> Seek for GuestFile<'a, S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let seek = self.qga.execute(&guest_file_seek(self.handle, pos)?)?;
+        Ok(seek.position as u64)
+    }
+}
+
+impl<'a, S: BufRead + Write> Drop for GuestFile<'a, S> {
+    fn drop(&mut self) {
+        if !self.closed {
+            if let Err(e) = self.qga.execute(&guest_file_close { handle: self.handle }) {
+                warn!("failed to close guest file handle {}: {}", self.handle, e);
+            }
+        }
+    }
+}
+
+impl<S: BufRead + Write> Qga<S> {
+    /// Opens a file within the guest, with an `fopen()` style `mode`
+    pub fn open_file(&mut self, path: &str, mode: &str) -> Result<GuestFile<'_, S>, ExecuteError> {
+        GuestFile::open(self, path, mode)
+    }
+
+    /// Copies a guest file into `write`, returning the number of bytes copied
+    pub fn copy_from_guest<W: Write + ?Sized>(&mut self, path: &str, write: &mut W) -> Result<u64, ExecuteError> {
+        let mut file = self.open_file(path, "r")?;
+        let count = io::copy(&mut file, write)?;
+        file.close()?;
+        Ok(count)
+    }
+
+    /// Copies `read` into a guest file, which is created or truncated
+    pub fn copy_to_guest<R: Read + ?Sized>(&mut self, read: &mut R, path: &str) -> Result<u64, ExecuteError> {
+        let mut file = self.open_file(path, "w")?;
+        let count = io::copy(read, &mut file)?;
+        file.close()?;
+        Ok(count)
+    }
+
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, ExecuteError> {
+        let mut data = Vec::new();
+        self.copy_from_guest(path, &mut data)?;
+        Ok(data)
+    }
+
+    pub fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), ExecuteError> {
+        self.copy_to_guest(&mut &data[..], path)
+            .map(drop)
+    }
+}
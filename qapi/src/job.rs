@@ -0,0 +1,265 @@
+use std::io::{self, BufRead, Write};
+use qapi_qmp::{
+    query_jobs, job_pause, job_resume, job_cancel, job_complete, job_finalize, job_dismiss,
+    Event, JobInfo, JobStatus,
+};
+use crate::{Qmp, Command, Enum, ExecuteError};
+
+pub(crate) fn job_status_change(event: &Event, id: &str) -> Option<JobStatus> {
+    match event {
+        Event::JOB_STATUS_CHANGE { data, .. } if data.id == id => Some(data.status),
+        _ => None,
+    }
+}
+
+pub(crate) fn job_reached(status: JobStatus, target: JobStatus) -> bool {
+    // auto-dismissed jobs pass straight through concluded
+    status == target || (target == JobStatus::concluded && status == JobStatus::null)
+}
+
+pub(crate) fn job_find(jobs: Vec<JobInfo>, id: &str) -> Option<JobInfo> {
+    jobs.into_iter().find(|job| job.id == id)
+}
+
+pub(crate) fn job_not_found(id: &str) -> ExecuteError {
+    io::Error::new(io::ErrorKind::NotFound, format!("job {} not found", id)).into()
+}
+
+/// The job's own error if it has one, as it finished without reaching `target`
+pub(crate) fn job_unreachable(id: &str, info: Option<&JobInfo>, target: JobStatus) -> ExecuteError {
+    match info.map(JobInfo::result) {
+        Some(Err(e)) => e.into(),
+        _ => io::Error::other(format!("job {} finished before reaching {}", id, target.name())).into(),
+    }
+}
+
+/// A handle to a running job, such as a block job, following the generic QAPI job state machine
+///
+/// Its status is tracked from `JOB_STATUS_CHANGE` events, which are removed from the [`Qmp`] event
+/// queue as they are seen. Other events remain queued.
+pub struct Job<'a, S> {
+    qmp: &'a mut Qmp<S>,
+    id: String,
+    status: JobStatus,
+}
+
+impl<'a, S: BufRead + Write> Job<'a, S> {
+    /// Looks up a job that has already been started
+    pub fn new<I: Into<String>>(qmp: &'a mut Qmp<S>, id: I) -> Result<Self, ExecuteError> {
+        let mut job = Job {
+            qmp,
+            id: id.into(),
+            status: JobStatus::undefined,
+        };
+        job.info()?;
+        Ok(job)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The most recently observed status
+    pub fn status(&self) -> JobStatus {
+        self.status
+    }
+
+    pub fn qmp(&mut self) -> &mut Qmp<S> {
+        self.qmp
+    }
+
+    fn query(&mut self) -> Result<Option<JobInfo>, ExecuteError> {
+        let jobs = self.qmp.execute(&query_jobs { })?;
+        // any status changes queued ahead of the response are already reflected in it
+        let id = &self.id;
+        while self.qmp.take_event(|e| job_status_change(e, id).is_some()).is_some() { }
+
+        let info = job_find(jobs, &self.id);
+        self.status = info.as_ref().map(|info| info.status).unwrap_or(JobStatus::null);
+        Ok(info)
+    }
+
+    /// Queries the job's progress, and refreshes its status
+    pub fn info(&mut self) -> Result<JobInfo, ExecuteError> {
+        self.query()?.ok_or_else(|| job_not_found(&self.id))
+    }
+
+    fn try_next_status(&mut self) -> Option<JobStatus> {
+        let id = &self.id;
+        let status = self.qmp.take_event(|e| job_status_change(e, id).is_some())
+            .and_then(|e| job_status_change(&e, id))?;
+        self.status = status;
+        Some(status)
+    }
+
+    /// Blocks until the job's status changes
+    pub fn next_status(&mut self) -> Result<JobStatus, ExecuteError> {
+        loop {
+            if let Some(status) = self.try_next_status() {
+                return Ok(status)
+            }
+            self.qmp.read_event()?;
+        }
+    }
+
+    /// Blocks until the job reaches `target`, failing if it finishes first
+    pub fn wait_for(&mut self, target: JobStatus) -> Result<(), ExecuteError> {
+        loop {
+            if job_reached(self.status, target) {
+                return Ok(())
+            }
+            if self.status.is_finished() {
+                let info = self.query()?;
+                return Err(job_unreachable(&self.id, info.as_ref(), target))
+            }
+            self.next_status()?;
+        }
+    }
+
+    /// Blocks until the job concludes, failing with its [`JobError`](qapi_qmp::JobError) if it has one
+    ///
+    /// The error is unavailable once the job has been dismissed, so jobs should be started with
+    /// `auto-dismiss` disabled in order to observe it.
+    pub fn wait_concluded(&mut self) -> Result<(), ExecuteError> {
+        self.wait_for(JobStatus::concluded)?;
+        match self.status {
+            JobStatus::concluded => match self.query()? {
+                Some(info) => info.result().map_err(From::from),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs the job to the end, completing, finalizing and dismissing it as needed, and reports its error
+    ///
+    /// A job that becomes `ready`, such as a mirror that has caught up with its source, is completed.
+    pub fn finish(&mut self) -> Result<(), ExecuteError> {
+        // each manual transition is requested once, then awaited until the status changes
+        let mut requested = None;
+        loop {
+            // act only on the latest status, as an automatic job may already have moved on
+            while self.try_next_status().is_some() { }
+
+            match self.status {
+                status if requested == Some(status) => {
+                    self.next_status()?;
+                },
+                status @ JobStatus::ready | status @ JobStatus::pending => {
+                    requested = Some(status);
+                    let res = match status {
+                        JobStatus::ready => self.execute(job_complete { id: self.id.clone() }),
+                        _ => self.execute(job_finalize { id: self.id.clone() }),
+                    };
+                    if res.is_err() && self.query()?.is_some_and(|info| info.status == status) {
+                        return res
+                    }
+                },
+                JobStatus::concluded => {
+                    let res = match self.query()? {
+                        Some(info) => info.result(),
+                        None => return Ok(()),
+                    };
+                    let dismissed = self.execute(job_dismiss { id: self.id.clone() });
+                    if dismissed.is_err() && self.query()?.is_some() {
+                        return dismissed
+                    }
+                    self.status = JobStatus::null;
+                    return res.map_err(From::from)
+                },
+                JobStatus::null => return Ok(()),
+                _ => {
+                    self.next_status()?;
+                },
+            }
+        }
+    }
+
+    fn execute<C: Command>(&mut self, command: C) -> Result<(), ExecuteError> {
+        self.qmp.execute(&command).map(drop)
+    }
+
+    pub fn pause(&mut self) -> Result<(), ExecuteError> {
+        self.execute(job_pause { id: self.id.clone() })
+    }
+
+    pub fn resume(&mut self) -> Result<(), ExecuteError> {
+        self.execute(job_resume { id: self.id.clone() })
+    }
+
+    pub fn cancel(&mut self) -> Result<(), ExecuteError> {
+        self.execute(job_cancel { id: self.id.clone() })
+    }
+
+    /// Completes a job that is ready, such as a mirror that has caught up with its source
+    pub fn complete(&mut self) -> Result<(), ExecuteError> {
+        self.execute(job_complete { id: self.id.clone() })
+    }
+
+    pub fn finalize(&mut self) -> Result<(), ExecuteError> {
+        self.execute(job_finalize { id: self.id.clone() })
+    }
+
+    pub fn dismiss(&mut self) -> Result<(), ExecuteError> {
+        self.execute(job_dismiss { id: self.id.clone() })?;
+        self.status = JobStatus::null;
+        Ok(())
+    }
+}
+
+impl<S: BufRead + Write> Qmp<S> {
+    /// Tracks a job that has already been started, by its job ID
+    pub fn job<I: Into<String>>(&mut self, id: I) -> Result<Job<'_, S>, ExecuteError> {
+        Job::new(self, id)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use qapi_qmp::JobStatus;
+    use crate::{Qmp, QapiReplay, ExecuteError};
+
+    /// A mirror that has just become ready, with the status changes that led there queued ahead of the query
+    pub(crate) const TRANSCRIPT: &str = r#"
+{"elapsed":0.0,"direction":"received","line":{"QMP":{"version":{"qemu":{"major":8,"minor":2,"micro":0},"package":""},"capabilities":[]}}}
+{"elapsed":0.0,"direction":"sent","line":{"execute":"qmp_capabilities","arguments":{},"id":0}}
+{"elapsed":0.0,"direction":"received","line":{"return":{},"id":0}}
+{"elapsed":0.1,"direction":"sent","line":{"execute":"query-jobs","arguments":{},"id":1}}
+{"elapsed":0.1,"direction":"received","line":{"event":"JOB_STATUS_CHANGE","data":{"id":"job0","status":"running"},"timestamp":{"seconds":1,"microseconds":0}}}
+{"elapsed":0.1,"direction":"received","line":{"event":"JOB_STATUS_CHANGE","data":{"id":"job0","status":"ready"},"timestamp":{"seconds":1,"microseconds":0}}}
+{"elapsed":0.1,"direction":"received","line":{"return":[{"id":"job0","type":"mirror","status":"ready","current-progress":1,"total-progress":1}],"id":1}}
+{"elapsed":0.2,"direction":"sent","line":{"execute":"job-complete","arguments":{"id":"job0"},"id":2}}
+{"elapsed":0.2,"direction":"received","line":{"event":"JOB_STATUS_CHANGE","data":{"id":"job0","status":"waiting"},"timestamp":{"seconds":2,"microseconds":0}}}
+{"elapsed":0.2,"direction":"received","line":{"return":{},"id":2}}
+{"elapsed":0.3,"direction":"received","line":{"event":"JOB_STATUS_CHANGE","data":{"id":"job1","status":"pending"},"timestamp":{"seconds":3,"microseconds":0}}}
+{"elapsed":0.3,"direction":"received","line":{"event":"JOB_STATUS_CHANGE","data":{"id":"job0","status":"pending"},"timestamp":{"seconds":3,"microseconds":0}}}
+{"elapsed":0.4,"direction":"sent","line":{"execute":"job-finalize","arguments":{"id":"job0"},"id":3}}
+{"elapsed":0.4,"direction":"received","line":{"event":"JOB_STATUS_CHANGE","data":{"id":"job0","status":"concluded"},"timestamp":{"seconds":4,"microseconds":0}}}
+{"elapsed":0.4,"direction":"received","line":{"return":{},"id":3}}
+{"elapsed":0.5,"direction":"sent","line":{"execute":"query-jobs","arguments":{},"id":4}}
+{"elapsed":0.5,"direction":"received","line":{"return":[{"id":"job0","type":"mirror","status":"concluded","current-progress":1,"total-progress":1,"error":"boom"}],"id":4}}
+{"elapsed":0.6,"direction":"sent","line":{"execute":"job-dismiss","arguments":{"id":"job0"},"id":5}}
+{"elapsed":0.6,"direction":"received","line":{"event":"JOB_STATUS_CHANGE","data":{"id":"job0","status":"null"},"timestamp":{"seconds":5,"microseconds":0}}}
+{"elapsed":0.6,"direction":"received","line":{"return":{},"id":5}}
+"#;
+
+    #[test]
+    fn finish() {
+        let replay = QapiReplay::from_reader(TRANSCRIPT.as_bytes()).unwrap();
+        let mut qmp = Qmp::from_stream(replay.clone());
+        qmp.handshake().unwrap();
+
+        let mut job = qmp.job("job0").unwrap();
+        assert_eq!(job.status(), JobStatus::ready);
+        assert_eq!(job.try_next_status(), None);
+        match job.finish() {
+            Err(ExecuteError::Job(e)) => assert_eq!(e.error, "boom"),
+            res => panic!("unexpected {:?}", res),
+        }
+        assert_eq!(job.status(), JobStatus::null);
+
+        // neither the other job's status change nor the one that accompanied the dismissal was seen
+        assert_eq!(qmp.events().count(), 2);
+        replay.finish().unwrap();
+    }
+}
@@ -6,10 +6,12 @@ pub use qapi_qmp as qmp;
 #[cfg(feature = "qapi-qga")]
 pub use qapi_qga as qga;
 
-pub use qapi_spec::{Any, Dictionary, Empty, Never, Execute, ExecuteOob, Command, CommandResult, Event, Enum, Error, ErrorClass, Timestamp};
+pub use qapi_spec::{Any, Dictionary, Empty, Never, Execute, ExecuteOob, ExecuteRaw, Command, OobCommand, CommandResult, Event, Enum, Error, ErrorClass, Timestamp};
 
 pub use self::stream::Stream;
 
+pub use self::transcript::{QapiRecorder, QapiReplay, QapiTranscriptWriter, QapiTranscriptEntry, QapiTranscriptDirection};
+
 #[cfg(feature = "qapi-qmp")]
 pub use self::qmp_impl::*;
 
@@ -21,10 +23,40 @@ use std::{error, fmt, io};
 #[cfg(feature = "async")]
 pub mod futures;
 
+mod transcript;
+
+#[cfg(feature = "qapi-qga")]
+mod guest_exec;
+#[cfg(feature = "qapi-qga")]
+pub use self::guest_exec::QgaExecStream;
+
+#[cfg(feature = "qapi-qga")]
+mod guest_file;
+#[cfg(feature = "qapi-qga")]
+pub use self::guest_file::{GuestFile, GUEST_FILE_CHUNK_SIZE};
+
+#[cfg(feature = "qapi-qga")]
+mod fsfreeze;
+#[cfg(feature = "qapi-qga")]
+pub use self::fsfreeze::QgaFreezeGuard;
+
+#[cfg(feature = "qapi-qmp")]
+mod transaction;
+#[cfg(feature = "qapi-qmp")]
+pub use self::transaction::{QmpTransaction, QmpTransactionError};
+
+#[cfg(feature = "qapi-qmp")]
+mod job;
+#[cfg(feature = "qapi-qmp")]
+pub use self::job::Job;
+
 #[derive(Debug)]
 pub enum ExecuteError {
     Qapi(Error),
     Io(io::Error),
+    /// A job that was being waited on failed or was aborted
+    #[cfg(feature = "qapi-qmp")]
+    Job(qapi_qmp::JobError),
 }
 
 pub type ExecuteResult<C> = Result<<C as Command>::Ok, ExecuteError>;
@@ -34,6 +66,8 @@ impl fmt::Display for ExecuteError {
         match self {
             ExecuteError::Qapi(e) => fmt::Display::fmt(e, f),
             ExecuteError::Io(e) => fmt::Display::fmt(e, f),
+            #[cfg(feature = "qapi-qmp")]
+            ExecuteError::Job(e) => fmt::Display::fmt(e, f),
         }
     }
 }
@@ -43,6 +77,8 @@ impl error::Error for ExecuteError {
         match self {
             ExecuteError::Qapi(e) => Some(e),
             ExecuteError::Io(e) => Some(e),
+            #[cfg(feature = "qapi-qmp")]
+            ExecuteError::Job(e) => Some(e),
         }
     }
 }
@@ -59,11 +95,27 @@ impl From<Error> for ExecuteError {
     }
 }
 
+#[cfg(feature = "qapi-qmp")]
+impl From<qapi_qmp::QemuUnsupported> for ExecuteError {
+    fn from(e: qapi_qmp::QemuUnsupported) -> Self {
+        ExecuteError::Io(e.into())
+    }
+}
+
+#[cfg(feature = "qapi-qmp")]
+impl From<qapi_qmp::JobError> for ExecuteError {
+    fn from(e: qapi_qmp::JobError) -> Self {
+        ExecuteError::Job(e)
+    }
+}
+
 impl From<ExecuteError> for io::Error {
     fn from(e: ExecuteError) -> Self {
         match e {
             ExecuteError::Qapi(e) => e.into(),
             ExecuteError::Io(e) => e,
+            #[cfg(feature = "qapi-qmp")]
+            ExecuteError::Job(e) => e.into(),
         }
     }
 }
@@ -73,7 +125,7 @@ mod qapi {
     use serde_json;
     use serde::{Serialize, Deserialize};
     use std::io::{self, BufRead, Write};
-    use crate::{Command, Execute};
+    use crate::{Any, Command, Execute, ExecuteRaw};
     use log::trace;
 
     pub struct Qapi<S> {
@@ -124,6 +176,15 @@ mod qapi {
 
             Ok(())
         }
+
+        pub fn write_command_raw(&mut self, name: &str, arguments: Any) -> io::Result<()> {
+            let command: ExecuteRaw = ExecuteRaw::new(name, arguments, None);
+            self.encode_line(&command)?;
+
+            trace!("-> execute {}: {}", name, serde_json::to_string_pretty(&command.arguments).unwrap());
+
+            Ok(())
+        }
     }
 }
 
@@ -184,12 +245,14 @@ mod stream {
 mod qmp_impl {
     use std::io::{self, BufRead, Read, Write, BufReader};
     use std::vec::Drain;
-    use qapi_qmp::{QMP, QapiCapabilities, QmpMessage, Event, qmp_capabilities, query_version};
-    use crate::{qapi::Qapi, Stream, ExecuteResult, ExecuteError, Command};
+    use qapi_qmp::{QMP, QapiCapabilities, QmpMessage, QmpSchema, QemuVersion, HmpInfo, Event, qmp_capabilities, query_version, human_monitor_command, hmp_output};
+    use serde::{Deserialize, de::DeserializeOwned};
+    use crate::{qapi::Qapi, Stream, Any, ExecuteResult, ExecuteError, Command, QmpTransaction, QmpTransactionError};
 
     pub struct Qmp<S> {
         inner: Qapi<S>,
         event_queue: Vec<Event>,
+        version: Option<QemuVersion>,
     }
 
     impl<S: Read + Write + Clone> Qmp<Stream<BufReader<S>, S>> {
@@ -203,9 +266,15 @@ mod qmp_impl {
             Qmp {
                 inner: Qapi::new(stream),
                 event_queue: Default::default(),
+                version: None,
             }
         }
 
+        /// The version advertised by the QMP greeting, once it has been read
+        pub fn qemu_version(&self) -> Option<QemuVersion> {
+            self.version
+        }
+
         pub fn into_inner(self) -> S {
             self.inner.stream
         }
@@ -221,16 +290,40 @@ mod qmp_impl {
         pub fn events(&mut self) -> Drain<'_, Event> {
             self.event_queue.drain(..)
         }
+
+        /// Removes the oldest queued event that matches `predicate`, leaving the rest queued
+        pub(crate) fn take_event<F: FnMut(&Event) -> bool>(&mut self, predicate: F) -> Option<Event> {
+            let index = self.event_queue.iter().position(predicate)?;
+            Some(self.event_queue.remove(index))
+        }
     }
 
     impl<S: BufRead> Qmp<S> {
         pub fn read_capabilities(&mut self) -> io::Result<QMP> {
-            self.inner.decode_line().map(|v: Option<QapiCapabilities>|
+            let caps = self.inner.decode_line().map(|v: Option<QapiCapabilities>|
                 v.expect("unexpected eof").QMP
-            )
+            )?;
+            self.version = Some(caps.qemu_version());
+            Ok(caps)
         }
 
         pub fn read_response<C: Command>(&mut self) -> ExecuteResult<C> {
+            self.read_response_value()
+        }
+
+        /// Blocks until the next event arrives, and queues it
+        pub(crate) fn read_event(&mut self) -> io::Result<()> {
+            match self.inner.decode_line::<QmpMessage<Any>>()? {
+                None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "expected event")),
+                Some(QmpMessage::Event(e)) => {
+                    self.event_queue.push(e);
+                    Ok(())
+                },
+                Some(QmpMessage::Response(..)) => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected response while waiting for an event")),
+            }
+        }
+
+        fn read_response_value<T: DeserializeOwned>(&mut self) -> Result<T, ExecuteError> {
             loop {
                 match self.inner.decode_line()? {
                     None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "expected command response").into()),
@@ -251,6 +344,14 @@ mod qmp_impl {
             self.read_response::<C>()
         }
 
+        /// Executes a command by name, including any that the schema does not describe
+        ///
+        /// Null `arguments` are omitted from the request.
+        pub fn execute_raw(&mut self, name: &str, arguments: Any) -> Result<Any, ExecuteError> {
+            self.inner.write_command_raw(name, arguments)?;
+            self.read_response_value()
+        }
+
         pub fn handshake(&mut self) -> Result<QMP, ExecuteError> {
             let caps = self.read_capabilities()?;
             self.execute(&qmp_capabilities { enable: None })
@@ -263,18 +364,61 @@ mod qmp_impl {
                 .map_err(From::from)
                 .map(drop)
         }
+
+        /// Introspects the commands, events and types that this QEMU supports
+        pub fn query_schema(&mut self) -> Result<QmpSchema, ExecuteError> {
+            let schema = self.execute_raw("query-qmp-schema", Any::Null)?;
+            QmpSchema::deserialize(schema)
+                .map_err(|e| io::Error::from(e).into())
+        }
+
+        /// Runs an HMP command, failing with `CommandNotFound` if this QEMU build lacks it
+        pub fn hmp(&mut self, command_line: &str) -> Result<String, ExecuteError> {
+            let output = self.execute(&human_monitor_command::new(command_line))?;
+            hmp_output(command_line, output).map_err(From::from)
+        }
+
+        /// Runs and parses an HMP `info` command
+        pub fn hmp_info<T: HmpInfo>(&mut self) -> Result<T, ExecuteError> {
+            let output = self.hmp(T::COMMAND)?;
+            T::parse(&output).map_err(From::from)
+        }
+
+        /// Executes a `transaction`, reporting which action caused it to fail
+        pub fn transaction(&mut self, transaction: &QmpTransaction) -> Result<(), QmpTransactionError> {
+            let command = transaction.command()?;
+            transaction.result(self.execute(&command))
+        }
     }
 }
 
 #[cfg(feature = "qapi-qga")]
 mod qga_impl {
     use std::io::{self, BufRead, Read, Write, BufReader};
-    use qapi_qga::guest_sync;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use qapi_qga::{guest_sync, guest_sync_delimited};
     use qapi_spec::Response;
-    use crate::{qapi::Qapi, Stream, Command, ExecuteResult, ExecuteError};
+    use serde::de::DeserializeOwned;
+    use crate::{qapi::Qapi, Stream, Any, Command, ExecuteResult, ExecuteError};
+
+    /// Precedes a response to `guest-sync-delimited`, and is never valid JSON
+    const SENTINEL: u8 = 0xff;
+
+    /// An ID for `guest-sync` that is unlikely to match a stale response
+    pub(crate) fn sync_id() -> i32 {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|t| t.subsec_nanos()).unwrap_or_default();
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed) as u32;
+        ((nanos ^ count.rotate_left(20)) & i32::MAX as u32) as i32
+    }
 
     pub struct Qga<S> {
         inner: Qapi<S>,
+        resync: bool,
+        needs_sync: bool,
     }
 
     impl<S: Read + Write + Clone> Qga<Stream<BufReader<S>, S>> {
@@ -287,9 +431,18 @@ mod qga_impl {
         pub fn new(stream: S) -> Self {
             Qga {
                 inner: Qapi::new(stream),
+                resync: false,
+                needs_sync: false,
             }
         }
 
+        /// Resynchronises using `guest-sync-delimited` before the next command, and again
+        /// after any response fails to decode
+        pub fn set_auto_resync(&mut self, enable: bool) {
+            self.resync = enable;
+            self.needs_sync = enable;
+        }
+
         pub fn into_inner(self) -> S {
             self.inner.stream
         }
@@ -304,9 +457,26 @@ mod qga_impl {
     }
 
     impl<S: BufRead> Qga<S> {
+        /// Discards input up to and including the sentinel, returning false on EOF
+        fn discard_until_sentinel(&mut self) -> io::Result<bool> {
+            let buffer = &mut self.inner.buffer;
+            buffer.clear();
+            self.inner.stream.read_until(SENTINEL, buffer)?;
+            Ok(buffer.last() == Some(&SENTINEL))
+        }
+
         pub fn read_response<C: Command>(&mut self) -> ExecuteResult<C> {
+            self.read_response_value()
+        }
+
+        fn read_response_value<T: DeserializeOwned>(&mut self) -> Result<T, ExecuteError> {
             loop {
-                match self.inner.decode_line()?.map(|r: Response<_>| r.result()) {
+                let res = self.inner.decode_line().inspect_err(|e| {
+                    if self.resync && e.kind() == io::ErrorKind::InvalidData {
+                        self.needs_sync = true;
+                    }
+                });
+                match res?.map(|r: Response<_>| r.result()) {
                     None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "expected command response").into()),
                     Some(Ok(res)) => return Ok(res),
                     Some(Err(e)) => return Err(e.into()),
@@ -321,10 +491,50 @@ mod qga_impl {
         }
 
         pub fn execute<C: Command>(&mut self, command: &C) -> ExecuteResult<C> {
+            if self.needs_sync {
+                self.guest_sync_delimited(sync_id())?;
+            }
+
             self.write_command(command)?;
             self.read_response::<C>()
         }
 
+        /// Executes a command by name, including any that the schema does not describe
+        ///
+        /// Null `arguments` are omitted from the request.
+        pub fn execute_raw(&mut self, name: &str, arguments: Any) -> Result<Any, ExecuteError> {
+            if self.needs_sync {
+                self.guest_sync_delimited(sync_id())?;
+            }
+
+            self.inner.write_command_raw(name, arguments)?;
+            self.read_response_value()
+        }
+
+        /// Like `guest_sync`, but first discards any stale or partial data that may be
+        /// left in the channel from an earlier, interrupted exchange
+        pub fn guest_sync_delimited(&mut self, sync_value: i32) -> Result<(), ExecuteError> {
+            let sync = guest_sync_delimited {
+                id: sync_value.into(),
+            };
+
+            // flushes any partial command from the agent's parser
+            self.inner.stream.write_all(&[SENTINEL])?;
+            self.write_command(&sync)?;
+            if !self.discard_until_sentinel()? {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "expected guest-sync-delimited sentinel").into())
+            }
+
+            match self.read_response::<guest_sync_delimited>() {
+                Ok(r) if r == sync.id => {
+                    self.needs_sync = false;
+                    Ok(())
+                },
+                Ok(..) => Err(io::Error::new(io::ErrorKind::InvalidData, "guest-sync handshake failed").into()),
+                Err(e) => Err(e),
+            }
+        }
+
         pub fn guest_sync(&mut self, sync_value: i32) -> Result<(), ExecuteError> {
             let id = sync_value.into();
             let sync = guest_sync {
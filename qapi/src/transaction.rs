@@ -0,0 +1,300 @@
+use std::{error, fmt, io};
+use qapi_qmp::{
+    transaction, TransactionAction, TransactionActionKind, TransactionProperties, ActionCompletionMode,
+    Abort, BlockDirtyBitmap, BlockDirtyBitmapAdd, BlockDirtyBitmapMerge, BlockDirtyBitmapWrapper,
+    BlockdevSnapshot, BlockdevSnapshotSync, BlockdevSnapshotInternal, BlockdevBackup, DriveBackup,
+};
+use crate::{Any, Enum, Error, ErrorClass, ExecuteError};
+
+/// Collects the actions of a `transaction`, so that they succeed or fail together
+///
+/// Each method returns the index of the action it added, which [`QmpTransactionError::action`]
+/// refers to if the transaction fails.
+#[derive(Debug, Clone, Default)]
+pub struct QmpTransaction {
+    actions: Vec<TransactionAction>,
+    grouped: bool,
+}
+
+impl QmpTransaction {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn push<A: Into<TransactionAction>>(&mut self, action: A) -> usize {
+        self.actions.push(action.into());
+        self.actions.len() - 1
+    }
+
+    /// Fails the whole transaction, which is mostly useful for testing
+    pub fn abort(&mut self) -> usize {
+        self.push(Abort { })
+    }
+
+    pub fn snapshot(&mut self, snapshot: BlockdevSnapshot) -> usize {
+        self.push(snapshot)
+    }
+
+    pub fn snapshot_sync(&mut self, snapshot: BlockdevSnapshotSync) -> usize {
+        self.push(snapshot)
+    }
+
+    pub fn snapshot_internal(&mut self, snapshot: BlockdevSnapshotInternal) -> usize {
+        self.push(snapshot)
+    }
+
+    pub fn bitmap_add(&mut self, bitmap: BlockDirtyBitmapAdd) -> usize {
+        self.push(bitmap)
+    }
+
+    pub fn bitmap_remove(&mut self, bitmap: BlockDirtyBitmap) -> usize {
+        self.push(TransactionAction::block_dirty_bitmap_remove(BlockDirtyBitmapWrapper::from(bitmap)))
+    }
+
+    pub fn bitmap_clear(&mut self, bitmap: BlockDirtyBitmap) -> usize {
+        self.push(TransactionAction::block_dirty_bitmap_clear(BlockDirtyBitmapWrapper::from(bitmap)))
+    }
+
+    pub fn bitmap_enable(&mut self, bitmap: BlockDirtyBitmap) -> usize {
+        self.push(TransactionAction::block_dirty_bitmap_enable(BlockDirtyBitmapWrapper::from(bitmap)))
+    }
+
+    pub fn bitmap_disable(&mut self, bitmap: BlockDirtyBitmap) -> usize {
+        self.push(TransactionAction::block_dirty_bitmap_disable(BlockDirtyBitmapWrapper::from(bitmap)))
+    }
+
+    pub fn bitmap_merge(&mut self, merge: BlockDirtyBitmapMerge) -> usize {
+        self.push(merge)
+    }
+
+    pub fn blockdev_backup(&mut self, backup: BlockdevBackup) -> usize {
+        self.push(backup)
+    }
+
+    pub fn drive_backup(&mut self, backup: DriveBackup) -> usize {
+        self.push(backup)
+    }
+
+    /// Requests `completion-mode: grouped`, so that the backup jobs succeed or fail as one
+    ///
+    /// QEMU only accepts this when every action is a backup.
+    pub fn set_grouped(&mut self, grouped: bool) {
+        self.grouped = grouped;
+    }
+
+    pub fn is_grouped(&self) -> bool {
+        self.grouped
+    }
+
+    pub fn actions(&self) -> &[TransactionAction] {
+        &self.actions
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// Builds the command, first checking what QEMU would reject outright
+    pub fn command(&self) -> Result<transaction, QmpTransactionError> {
+        if self.grouped {
+            let unsupported = self.actions.iter().position(|a| !matches!(a.type_(),
+                TransactionActionKind::blockdev_backup | TransactionActionKind::drive_backup
+            ));
+            if let Some(index) = unsupported {
+                let kind = self.actions[index].type_();
+                let e = Error::new(ErrorClass::GenericError, format!("Action '{}' does not support transaction property completion-mode = grouped", kind.name()));
+                return Err(QmpTransactionError::new(Some(index), Some(kind), e.into()))
+            }
+        }
+
+        Ok(transaction {
+            actions: self.actions.clone(),
+            // omitted unless needed, as QEMU before 2.5 does not know about it
+            properties: if self.grouped {
+                Some(TransactionProperties {
+                    completion_mode: Some(ActionCompletionMode::grouped),
+                })
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Guesses which action a failed transaction's error refers to
+    ///
+    /// QEMU reports a single error for the whole transaction, so this looks for the action whose
+    /// node, device, bitmap or file names are quoted in the message. Returns `None` when no single
+    /// action stands out.
+    pub fn failed_action(&self, error: &Error) -> Option<usize> {
+        if error.desc.contains("Abort action") {
+            return single(self.actions.iter().enumerate()
+                .filter(|(_, a)| a.type_() == TransactionActionKind::abort)
+                .map(|(i, _)| i)
+            )
+        }
+
+        let scores: Vec<usize> = self.actions.iter().map(|action| {
+            let mut names = vec![action.type_().name().to_owned()];
+            if let Ok(value) = serde_json::to_value(action) {
+                strings(&value, &mut names);
+            }
+            names.iter()
+                .filter(|&name| !name.is_empty() && quoted(&error.desc, name))
+                .count()
+        }).collect();
+
+        let best = scores.iter().copied().max().unwrap_or_default();
+        if best == 0 {
+            return None
+        }
+        single(scores.iter().enumerate().filter(|&(_, &s)| s == best).map(|(i, _)| i))
+    }
+
+    fn map_error(&self, e: ExecuteError) -> QmpTransactionError {
+        let action = match e {
+            ExecuteError::Qapi(ref e) => self.failed_action(e),
+            ExecuteError::Io(..) | ExecuteError::Job(..) => None,
+        };
+        QmpTransactionError::new(action, action.map(|i| self.actions[i].type_()), e)
+    }
+
+    pub(crate) fn result(&self, res: Result<qapi_spec::Empty, ExecuteError>) -> Result<(), QmpTransactionError> {
+        res.map(drop).map_err(|e| self.map_error(e))
+    }
+}
+
+fn single<I: Iterator<Item=usize>>(mut iter: I) -> Option<usize> {
+    match (iter.next(), iter.next()) {
+        (Some(i), None) => Some(i),
+        _ => None,
+    }
+}
+
+fn strings(value: &Any, out: &mut Vec<String>) {
+    match value {
+        Any::String(s) => out.push(s.clone()),
+        Any::Array(a) => a.iter().for_each(|v| strings(v, out)),
+        Any::Object(o) => o.values().for_each(|v| strings(v, out)),
+        _ => (),
+    }
+}
+
+fn quoted(desc: &str, name: &str) -> bool {
+    ['\'', '"'].iter().any(|&q| desc.contains(&format!("{}{}{}", q, name, q)))
+}
+
+/// A transaction that failed, along with the action responsible when it could be determined
+#[derive(Debug)]
+pub struct QmpTransactionError {
+    /// The index of the action, as returned when it was added to the [`QmpTransaction`]
+    pub action: Option<usize>,
+    pub kind: Option<TransactionActionKind>,
+    pub error: ExecuteError,
+}
+
+impl QmpTransactionError {
+    pub fn new(action: Option<usize>, kind: Option<TransactionActionKind>, error: ExecuteError) -> Self {
+        Self {
+            action,
+            kind,
+            error,
+        }
+    }
+}
+
+impl fmt::Display for QmpTransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.action, self.kind) {
+            (Some(action), Some(kind)) => write!(f, "transaction action {} ({}) failed: {}", action, kind.name(), self.error),
+            _ => write!(f, "transaction failed: {}", self.error),
+        }
+    }
+}
+
+impl error::Error for QmpTransactionError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<QmpTransactionError> for ExecuteError {
+    fn from(e: QmpTransactionError) -> Self {
+        e.error
+    }
+}
+
+impl From<QmpTransactionError> for io::Error {
+    fn from(e: QmpTransactionError) -> Self {
+        e.error.into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use qapi_qmp::{BlockDirtyBitmap, BlockDirtyBitmapAdd, BlockdevBackup, MirrorSyncMode, TransactionActionKind};
+    use crate::{Error, ErrorClass};
+    use super::QmpTransaction;
+
+    fn backup(device: &str, bitmap: Option<&str>) -> BlockdevBackup {
+        BlockdevBackup {
+            target: format!("{}-backup", device),
+            base: qapi_qmp::BackupCommon {
+                job_id: None,
+                device: device.into(),
+                sync: if bitmap.is_some() { MirrorSyncMode::incremental } else { MirrorSyncMode::full },
+                speed: None,
+                bitmap: bitmap.map(Into::into),
+                bitmap_mode: None,
+                compress: None,
+                on_source_error: None,
+                on_target_error: None,
+                auto_finalize: None,
+                auto_dismiss: None,
+                filter_node_name: None,
+            },
+        }
+    }
+
+    #[test]
+    fn build_and_attribute() {
+        let mut t = QmpTransaction::new();
+        let add = t.bitmap_add(BlockDirtyBitmapAdd {
+            node: "drive0".into(),
+            name: "bitmap0".into(),
+            granularity: None,
+            persistent: None,
+            disabled: None,
+        });
+        t.bitmap_clear(BlockDirtyBitmap {
+            node: "drive1".into(),
+            name: "bitmap1".into(),
+        });
+        let backup2 = t.blockdev_backup(backup("drive2", None));
+
+        let command = serde_json::to_value(t.command().unwrap()).unwrap();
+        assert!(command.get("properties").is_none());
+        assert_eq!(command["actions"][1]["type"], "block-dirty-bitmap-clear");
+        assert_eq!(command["actions"][1]["data"]["name"], "bitmap1");
+
+        let error = |desc: &str| Error::new(ErrorClass::GenericError, desc);
+        assert_eq!(t.failed_action(&error("Bitmap 'bitmap0' already exists")), Some(add));
+        assert_eq!(t.failed_action(&error("Cannot find device='drive2' nor node-name=''")), Some(backup2));
+        assert_eq!(t.failed_action(&error("Permission denied")), None);
+
+        t.set_grouped(true);
+        let e = t.command().unwrap_err();
+        assert_eq!(e.action, Some(add));
+        assert_eq!(e.kind, Some(TransactionActionKind::block_dirty_bitmap_add));
+
+        let mut t = QmpTransaction::new();
+        t.blockdev_backup(backup("drive0", Some("bitmap0")));
+        t.blockdev_backup(backup("drive1", None));
+        t.set_grouped(true);
+        let command = serde_json::to_value(t.command().unwrap()).unwrap();
+        assert_eq!(command["properties"]["completion-mode"], "grouped");
+    }
+}
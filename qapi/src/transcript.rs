@@ -0,0 +1,513 @@
+use std::io::{self, Read, Write};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use serde::{Serialize, Deserialize};
+use log::warn;
+use crate::Any;
+
+/// Which side of a connection a transcript line came from
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QapiTranscriptDirection {
+    /// Written by the client
+    Sent,
+    /// Read by the client
+    Received,
+}
+
+/// A single line of a JSONL transcript
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QapiTranscriptEntry {
+    /// Seconds since recording started
+    pub elapsed: f64,
+    pub direction: QapiTranscriptDirection,
+    /// The message, or the raw line as a string if it was not valid JSON
+    pub line: Any,
+}
+
+impl QapiTranscriptEntry {
+    fn new(elapsed: f64, direction: QapiTranscriptDirection, line: &[u8]) -> Self {
+        Self {
+            elapsed,
+            direction,
+            line: serde_json::from_slice(line)
+                .unwrap_or_else(|_| Any::String(String::from_utf8_lossy(line).into_owned())),
+        }
+    }
+
+    /// Parses a transcript, one entry per line
+    pub fn read_all<R: io::BufRead>(read: R) -> io::Result<Vec<Self>> {
+        read.lines()
+            .filter(|line| line.as_ref().map(|l| !l.trim().is_empty()).unwrap_or(true))
+            .map(|line| serde_json::from_str(&line?).map_err(From::from))
+            .collect()
+    }
+}
+
+/// Writes transcript entries, and may be shared by the recorders of both halves of a connection
+#[derive(Clone)]
+pub struct QapiTranscriptWriter {
+    write: Arc<Mutex<Box<dyn Write + Send>>>,
+    start: Instant,
+}
+
+impl QapiTranscriptWriter {
+    pub fn new<W: Write + Send + 'static>(write: W) -> Self {
+        Self {
+            write: Arc::new(Mutex::new(Box::new(write))),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn record(&self, direction: QapiTranscriptDirection, line: &[u8]) {
+        let entry = QapiTranscriptEntry::new(self.start.elapsed().as_secs_f64(), direction, line);
+        let mut write = self.write.lock().unwrap();
+        let res = serde_json::to_writer(&mut *write, &entry).map_err(io::Error::from)
+            .and_then(|()| write.write_all(b"\n"))
+            .and_then(|()| write.flush());
+        if let Err(e) = res {
+            warn!("Failed to record QAPI transcript: {}", e);
+        }
+    }
+}
+
+/// Records every complete line that passes through a stream
+pub struct QapiRecorder<S> {
+    inner: S,
+    transcript: QapiTranscriptWriter,
+    read_line: Vec<u8>,
+    write_line: Vec<u8>,
+}
+
+impl<S> QapiRecorder<S> {
+    pub fn new(inner: S, transcript: QapiTranscriptWriter) -> Self {
+        Self {
+            inner,
+            transcript,
+            read_line: Vec::new(),
+            write_line: Vec::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn record(transcript: &QapiTranscriptWriter, partial: &mut Vec<u8>, direction: QapiTranscriptDirection, data: &[u8]) {
+        for chunk in data.split_inclusive(|&c| c == b'\n') {
+            partial.extend_from_slice(chunk);
+            if partial.last() == Some(&b'\n') {
+                transcript.record(direction, &partial[..partial.len() - 1]);
+                partial.clear();
+            }
+        }
+    }
+
+    fn record_read(&mut self, data: &[u8]) {
+        Self::record(&self.transcript, &mut self.read_line, QapiTranscriptDirection::Received, data)
+    }
+
+    fn record_write(&mut self, data: &[u8]) {
+        Self::record(&self.transcript, &mut self.write_line, QapiTranscriptDirection::Sent, data)
+    }
+}
+
+impl<S: Clone> Clone for QapiRecorder<S> {
+    fn clone(&self) -> Self {
+        Self::new(self.inner.clone(), self.transcript.clone())
+    }
+}
+
+impl<S: Read> Read for QapiRecorder<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.record_read(&buf[..len]);
+        Ok(len)
+    }
+}
+
+impl<S: Write> Write for QapiRecorder<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.record_write(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(feature = "tokio")]
+mod tokio_impl {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use super::{QapiRecorder, QapiReplay, ReplayRead};
+
+    impl<S: AsyncRead> AsyncRead for QapiRecorder<S> {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+            let this = unsafe { self.get_unchecked_mut() };
+            let inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+            let filled = buf.filled().len();
+            let res = inner.poll_read(cx, buf);
+            this.record_read(&buf.filled()[filled..]);
+            res
+        }
+    }
+
+    impl<S: AsyncWrite> AsyncWrite for QapiRecorder<S> {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            let this = unsafe { self.get_unchecked_mut() };
+            let inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+            let res = inner.poll_write(cx, buf);
+            if let Poll::Ready(Ok(len)) = res {
+                this.record_write(&buf[..len]);
+            }
+            res
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            unsafe { self.map_unchecked_mut(|this| &mut this.inner) }.poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            unsafe { self.map_unchecked_mut(|this| &mut this.inner) }.poll_shutdown(cx)
+        }
+    }
+
+    impl AsyncRead for QapiReplay {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+            let mut state = self.state.lock().unwrap();
+            match state.read(buf.initialize_unfilled()) {
+                ReplayRead::Data(len) => {
+                    buf.advance(len);
+                    Poll::Ready(Ok(()))
+                },
+                ReplayRead::Eof => Poll::Ready(Ok(())),
+                ReplayRead::Blocked => {
+                    state.reader = Some(cx.waker().clone());
+                    Poll::Pending
+                },
+            }
+        }
+    }
+
+    impl AsyncWrite for QapiReplay {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(self.state.lock().unwrap().write(buf))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+#[cfg(feature = "async-futures-io")]
+mod futures_impl {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use futures::io::{AsyncRead, AsyncWrite};
+    use super::{QapiRecorder, QapiReplay, ReplayRead};
+
+    impl<S: AsyncRead> AsyncRead for QapiRecorder<S> {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            let this = unsafe { self.get_unchecked_mut() };
+            let inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+            let res = inner.poll_read(cx, buf);
+            if let Poll::Ready(Ok(len)) = res {
+                this.record_read(&buf[..len]);
+            }
+            res
+        }
+    }
+
+    impl<S: AsyncWrite> AsyncWrite for QapiRecorder<S> {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            let this = unsafe { self.get_unchecked_mut() };
+            let inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+            let res = inner.poll_write(cx, buf);
+            if let Poll::Ready(Ok(len)) = res {
+                this.record_write(&buf[..len]);
+            }
+            res
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            unsafe { self.map_unchecked_mut(|this| &mut this.inner) }.poll_flush(cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            unsafe { self.map_unchecked_mut(|this| &mut this.inner) }.poll_close(cx)
+        }
+    }
+
+    impl AsyncRead for QapiReplay {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            let mut state = self.state.lock().unwrap();
+            match state.read(buf) {
+                ReplayRead::Data(len) => Poll::Ready(Ok(len)),
+                ReplayRead::Eof => Poll::Ready(Ok(0)),
+                ReplayRead::Blocked => {
+                    state.reader = Some(cx.waker().clone());
+                    Poll::Pending
+                },
+            }
+        }
+    }
+
+    impl AsyncWrite for QapiReplay {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(self.state.lock().unwrap().write(buf))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+enum ReplayRead {
+    Data(usize),
+    Eof,
+    Blocked,
+}
+
+struct ReplayState {
+    entries: Vec<QapiTranscriptEntry>,
+    // next entry to deliver to the reader
+    read_pos: usize,
+    // next entry to match against what the client writes
+    write_pos: usize,
+    // the remainder of the line being read
+    pending: Vec<u8>,
+    // a partially written line
+    line: Vec<u8>,
+    // recorded ids, as JSON, mapped to those the client actually used until their response is read
+    ids: HashMap<String, Any>,
+    reader: Option<std::task::Waker>,
+}
+
+impl ReplayState {
+    fn next_line(&mut self) -> Option<bool> {
+        while let Some(entry) = self.entries.get(self.read_pos) {
+            match entry.direction {
+                QapiTranscriptDirection::Sent if self.read_pos < self.write_pos => (),
+                QapiTranscriptDirection::Sent => return Some(false),
+                QapiTranscriptDirection::Received => {
+                    let mut line = entry.line.clone();
+                    if let Some(id) = line.get_mut("id") {
+                        if let Some(actual) = self.ids.remove(&id.to_string()) {
+                            *id = actual;
+                        }
+                    }
+                    self.pending = match line {
+                        Any::String(raw) => raw.into_bytes(),
+                        line => serde_json::to_vec(&line).expect("transcript line"),
+                    };
+                    self.pending.push(b'\n');
+                },
+            }
+            self.read_pos += 1;
+            if !self.pending.is_empty() {
+                return Some(true)
+            }
+        }
+
+        None
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> ReplayRead {
+        if self.pending.is_empty() {
+            match self.next_line() {
+                Some(true) => (),
+                Some(false) => return ReplayRead::Blocked,
+                None => return ReplayRead::Eof,
+            }
+        }
+
+        let len = buf.len().min(self.pending.len());
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        ReplayRead::Data(len)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|&c| c == b'\n') {
+            let line: Vec<u8> = self.line.drain(..=end).collect();
+            self.expect(&line[..end])?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn expect(&mut self, line: &[u8]) -> io::Result<()> {
+        let mut actual = QapiTranscriptEntry::new(0.0, QapiTranscriptDirection::Sent, line).line;
+        let offset = self.entries[self.write_pos..].iter()
+            .position(|e| e.direction == QapiTranscriptDirection::Sent)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("QAPI replay ended, but the client sent {}", actual)))?;
+        let mut expected = self.entries[self.write_pos + offset].line.clone();
+
+        let actual_id = actual.as_object_mut().and_then(|o| o.remove("id"));
+        let expected_id = expected.as_object_mut().and_then(|o| o.remove("id"));
+        if actual != expected {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("QAPI replay expected {}, but the client sent {}", expected, actual)))
+        }
+        if let (Some(expected), Some(actual)) = (expected_id, actual_id) {
+            self.ids.insert(expected.to_string(), actual);
+        }
+
+        self.write_pos += offset + 1;
+        if let Some(waker) = self.reader.take() {
+            waker.wake()
+        }
+        Ok(())
+    }
+}
+
+/// A fake transport that plays back a transcript
+///
+/// Each line the client writes must match the next command that was sent, ignoring ids. Recorded
+/// responses and events are read back in order, but never before the commands that preceded them.
+#[derive(Clone)]
+pub struct QapiReplay {
+    state: Arc<Mutex<ReplayState>>,
+}
+
+impl QapiReplay {
+    pub fn new(entries: Vec<QapiTranscriptEntry>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ReplayState {
+                entries,
+                read_pos: 0,
+                write_pos: 0,
+                pending: Vec::new(),
+                line: Vec::new(),
+                ids: HashMap::new(),
+                reader: None,
+            })),
+        }
+    }
+
+    pub fn from_reader<R: io::BufRead>(read: R) -> io::Result<Self> {
+        QapiTranscriptEntry::read_all(read).map(Self::new)
+    }
+
+    /// Fails unless every line of the transcript has been sent and received
+    pub fn finish(&self) -> io::Result<()> {
+        let state = self.state.lock().unwrap();
+        let remaining = state.entries.iter().enumerate()
+            .find(|&(i, e)| match e.direction {
+                QapiTranscriptDirection::Sent => i >= state.write_pos,
+                QapiTranscriptDirection::Received => i >= state.read_pos,
+            });
+        match remaining {
+            Some((_, e)) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("QAPI replay did not reach {}", e.line))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Read for QapiReplay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        match state.read(buf) {
+            ReplayRead::Data(len) => Ok(len),
+            ReplayRead::Eof => Ok(0),
+            ReplayRead::Blocked => {
+                let next = &state.entries[state.read_pos].line;
+                Err(io::Error::new(io::ErrorKind::InvalidInput, format!("QAPI replay is waiting for the client to send {}", next)))
+            },
+        }
+    }
+}
+
+impl Write for QapiReplay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.state.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "qapi-qmp"))]
+mod test {
+    use std::io::{Read, Write};
+    use qapi_qmp::query_version;
+    use crate::Qmp;
+    use super::QapiReplay;
+
+    const TRANSCRIPT: &str = r#"
+{"elapsed":0.0,"direction":"received","line":{"QMP":{"version":{"qemu":{"major":8,"minor":2,"micro":0},"package":""},"capabilities":[]}}}
+{"elapsed":0.1,"direction":"sent","line":{"execute":"qmp_capabilities","arguments":{},"id":7}}
+{"elapsed":0.1,"direction":"received","line":{"return":{},"id":7}}
+{"elapsed":0.2,"direction":"received","line":{"event":"STOP","data":{},"timestamp":{"seconds":1,"microseconds":0}}}
+{"elapsed":0.3,"direction":"sent","line":{"execute":"query-version","arguments":{}}}
+{"elapsed":0.3,"direction":"received","line":{"return":{"qemu":{"major":8,"minor":2,"micro":0},"package":""}}}
+"#;
+
+    #[test]
+    fn replay() {
+        let mut replay = QapiReplay::from_reader(TRANSCRIPT.as_bytes()).unwrap();
+
+        let mut line = [0u8; 256];
+        let len = replay.read(&mut line).unwrap();
+        assert!(line[..len].starts_with(b"{\"QMP\""));
+        assert!(replay.read(&mut line).is_err());
+        replay.write_all(b"{\"execute\":\"qmp_capabilities\",\"arguments\":{},\"id\":0}\n").unwrap();
+        let len = replay.read(&mut line).unwrap();
+        assert_eq!(&line[..len], b"{\"id\":0,\"return\":{}}\n");
+        assert!(replay.state.lock().unwrap().ids.is_empty());
+
+        let mut qmp = Qmp::from_stream(replay.clone());
+        assert_eq!(qmp.execute(&query_version { }).unwrap().qemu.minor, 2);
+        assert_eq!(qmp.events().count(), 1);
+        replay.finish().unwrap();
+
+        let mut replay = QapiReplay::from_reader(TRANSCRIPT.as_bytes()).unwrap();
+        assert!(replay.write_all(b"{\"execute\":\"query-version\"}\n").is_err());
+    }
+
+    #[cfg(feature = "async-futures-io")]
+    #[test]
+    fn replay_futures_io() {
+        use futures::{FutureExt, io::{AsyncReadExt, AsyncWriteExt}};
+
+        let mut replay = QapiReplay::from_reader(TRANSCRIPT.as_bytes()).unwrap();
+        futures::executor::block_on(async {
+            let mut line = [0u8; 256];
+            let len = AsyncReadExt::read(&mut replay, &mut line).await.unwrap();
+            assert!(line[..len].starts_with(b"{\"QMP\""));
+            assert!(AsyncReadExt::read(&mut replay, &mut line).now_or_never().is_none());
+
+            let mut reader = replay.clone();
+            let read = async move {
+                let len = AsyncReadExt::read(&mut reader, &mut line).await.unwrap();
+                line[..len].to_vec()
+            };
+            let write = AsyncWriteExt::write_all(&mut replay, b"{\"execute\":\"qmp_capabilities\",\"arguments\":{},\"id\":3}\n");
+            let (line, res) = futures::join!(read, write);
+            res.unwrap();
+            assert_eq!(line, b"{\"id\":3,\"return\":{}}\n");
+        });
+    }
+}
@@ -0,0 +1,184 @@
+use std::time::Duration;
+use crate::{guest_exec, GuestExecStatus, GuestExecCaptureOutput};
+
+/// Builds a `guest-exec` command, in the manner of `std::process::Command`
+///
+/// Also describes how the process is waited on: `guest-exec-status` is polled with exponential
+/// backoff between [`poll_interval`](Self::poll_interval) and
+/// [`max_poll_interval`](Self::max_poll_interval), until it exits or the timeout elapses.
+#[derive(Debug, Clone)]
+pub struct GuestCommand {
+    path: String,
+    args: Vec<String>,
+    env: Vec<String>,
+    stdin: Option<Vec<u8>>,
+    capture_output: bool,
+    timeout: Option<Duration>,
+    poll_interval: Duration,
+    max_poll_interval: Duration,
+}
+
+impl GuestCommand {
+    pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+    pub const DEFAULT_MAX_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn new<S: Into<String>>(path: S) -> Self {
+        Self {
+            path: path.into(),
+            args: Vec::new(),
+            env: Vec::new(),
+            stdin: None,
+            capture_output: true,
+            timeout: None,
+            poll_interval: Self::DEFAULT_POLL_INTERVAL,
+            max_poll_interval: Self::DEFAULT_MAX_POLL_INTERVAL,
+        }
+    }
+
+    pub fn arg<S: Into<String>>(&mut self, arg: S) -> &mut Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I: IntoIterator<Item=S>, S: Into<String>>(&mut self, args: I) -> &mut Self {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sets an environment variable, which replaces the agent's environment entirely
+    pub fn env<K: AsRef<str>, V: AsRef<str>>(&mut self, key: K, value: V) -> &mut Self {
+        self.env.push(format!("{}={}", key.as_ref(), value.as_ref()));
+        self
+    }
+
+    pub fn envs<I: IntoIterator<Item=(K, V)>, K: AsRef<str>, V: AsRef<str>>(&mut self, vars: I) -> &mut Self {
+        for (key, value) in vars {
+            self.env(key, value);
+        }
+        self
+    }
+
+    /// Data written to the process's standard input, which is then closed
+    pub fn stdin<D: Into<Vec<u8>>>(&mut self, data: D) -> &mut Self {
+        self.stdin = Some(data.into());
+        self
+    }
+
+    /// Whether stdout and stderr are captured, which they are by default
+    pub fn capture_output(&mut self, capture: bool) -> &mut Self {
+        self.capture_output = capture;
+        self
+    }
+
+    /// How long to wait for the process to exit, without limit by default
+    ///
+    /// The guest process is left running if this elapses, as the agent cannot kill it.
+    pub fn timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn poll_interval(&mut self, initial: Duration, max: Duration) -> &mut Self {
+        self.poll_interval = initial;
+        self.max_poll_interval = max.max(initial);
+        self
+    }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// The delay before the status poll following `polls` earlier ones
+    pub fn poll_delay(&self, polls: u32) -> Duration {
+        self.poll_interval.checked_mul(1 << polls.min(16))
+            .map(|delay| delay.min(self.max_poll_interval))
+            .unwrap_or(self.max_poll_interval)
+    }
+
+    pub fn to_command(&self) -> guest_exec {
+        guest_exec {
+            path: self.path.clone(),
+            arg: non_empty(&self.args),
+            env: non_empty(&self.env),
+            input_data: self.stdin.clone(),
+            capture_output: if self.capture_output {
+                Some(GuestExecCaptureOutput::flag(true))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Runs the command through `/bin/sh`, appending its stdout and stderr to files within the
+    /// guest rather than capturing them
+    ///
+    /// Unlike captured output, the files can be read while the process is still running. This
+    /// requires a POSIX guest.
+    pub fn to_redirected_command(&self, stdout: &str, stderr: &str) -> guest_exec {
+        let mut arg = vec![
+            "-c".into(),
+            r#"out=$0 err=$1; shift; exec "$@" >>"$out" 2>>"$err""#.into(),
+            stdout.into(),
+            stderr.into(),
+            self.path.clone(),
+        ];
+        arg.extend(self.args.iter().cloned());
+        guest_exec {
+            path: "/bin/sh".into(),
+            arg: Some(arg),
+            env: non_empty(&self.env),
+            input_data: self.stdin.clone(),
+            capture_output: None,
+        }
+    }
+}
+
+fn non_empty(v: &[String]) -> Option<Vec<String>> {
+    if v.is_empty() { None } else { Some(v.to_vec()) }
+}
+
+/// Output from a guest process, as it becomes available
+///
+/// The agent only reports captured output once the process has exited, so output is only streamed
+/// while the process runs if it was redirected by [`GuestCommand::to_redirected_command`].
+#[derive(Debug, Clone)]
+pub enum GuestExecOutput {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+    /// The final status, without its captured output
+    Exited(GuestExecStatus),
+}
+
+impl GuestExecStatus {
+    /// Splits off any captured output, followed by the status itself if the process has exited
+    pub fn into_outputs(mut self) -> impl Iterator<Item=GuestExecOutput> {
+        let stdout = self.out_data.take().filter(|d| !d.is_empty()).map(GuestExecOutput::Stdout);
+        let stderr = self.err_data.take().filter(|d| !d.is_empty()).map(GuestExecOutput::Stderr);
+        let exited = if self.exited { Some(GuestExecOutput::Exited(self)) } else { None };
+        stdout.into_iter().chain(stderr).chain(exited)
+    }
+
+    /// Reassembles the final status of a process from its streamed output
+    pub fn from_outputs<I: IntoIterator<Item=GuestExecOutput>>(outputs: I) -> Option<Self> {
+        let mut stdout: Option<Vec<u8>> = None;
+        let mut stderr: Option<Vec<u8>> = None;
+        let mut status = None;
+        for output in outputs {
+            match output {
+                GuestExecOutput::Stdout(data) => stdout.get_or_insert_with(Vec::new).extend(data),
+                GuestExecOutput::Stderr(data) => stderr.get_or_insert_with(Vec::new).extend(data),
+                GuestExecOutput::Exited(s) => status = Some(s),
+            }
+        }
+
+        status.map(|status| GuestExecStatus {
+            out_data: stdout,
+            err_data: stderr,
+            .. status
+        })
+    }
+}
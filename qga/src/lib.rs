@@ -6,6 +6,9 @@ include!(concat!(env!("OUT_DIR"), "/qga.rs"));
 use std::{io, str, fmt, error};
 use serde::{Deserialize, Serialize};
 
+mod exec;
+pub use self::exec::{GuestCommand, GuestExecOutput};
+
 pub trait QgaCommand: qapi_spec::Command { }
 impl<'a, T: QgaCommand> QgaCommand for &'a T { }
 impl<'a, T: QgaCommand> QgaCommand for &'a mut T { }
@@ -0,0 +1,315 @@
+use std::io;
+use qapi_spec::{Error, ErrorClass};
+use crate::{RunState, human_monitor_command};
+
+impl human_monitor_command {
+    pub fn new<S: Into<String>>(command_line: S) -> Self {
+        human_monitor_command {
+            command_line: command_line.into(),
+            cpu_index: None,
+        }
+    }
+}
+
+/// Checks the output of `human-monitor-command`
+///
+/// HMP reports commands that are missing from the QEMU build as ordinary output rather than as an
+/// error, so this turns them into `CommandNotFound`.
+pub fn hmp_output(command_line: &str, output: String) -> Result<String, Error> {
+    match output.strip_prefix("unknown command: ") {
+        Some(unknown) => {
+            let name = unknown.trim().trim_matches('\'');
+            let name = if name.is_empty() { command_line } else { name };
+            Err(Error::new(ErrorClass::CommandNotFound, format!("HMP command '{}' is not available in this QEMU build", name)))
+        },
+        None => Ok(output),
+    }
+}
+
+/// The parsed output of an HMP `info` command
+pub trait HmpInfo: Sized {
+    /// The full command line, such as `info status`
+    const COMMAND: &'static str;
+
+    fn parse(output: &str) -> io::Result<Self>;
+}
+
+fn invalid(command: &str, line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("unexpected `{}` output: {:?}", command, line))
+}
+
+/// `info status`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HmpStatus {
+    pub running: bool,
+    pub single_step: bool,
+    /// Only reported when the VM is stopped for a reason other than `paused`
+    pub status: Option<RunState>,
+}
+
+impl HmpInfo for HmpStatus {
+    const COMMAND: &'static str = "info status";
+
+    fn parse(output: &str) -> io::Result<Self> {
+        let line = output.trim();
+        let status = line.strip_prefix("VM status: ")
+            .ok_or_else(|| invalid(Self::COMMAND, line))?;
+        let (running, rest) = if let Some(rest) = status.strip_prefix("running") {
+            (true, rest)
+        } else if let Some(rest) = status.strip_prefix("paused") {
+            (false, rest)
+        } else {
+            return Err(invalid(Self::COMMAND, line))
+        };
+        let single_step = rest.contains("(single step mode)");
+        let status = rest.replace("(single step mode)", "");
+        let status = status.trim().trim_start_matches('(').trim_end_matches(')');
+        Ok(HmpStatus {
+            running,
+            single_step,
+            status: if status.is_empty() {
+                None
+            } else {
+                Some(status.parse().map_err(|_| invalid(Self::COMMAND, line))?)
+            },
+        })
+    }
+}
+
+/// `info kvm`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HmpKvm {
+    Enabled,
+    Disabled,
+    NotCompiled,
+}
+
+impl HmpInfo for HmpKvm {
+    const COMMAND: &'static str = "info kvm";
+
+    fn parse(output: &str) -> io::Result<Self> {
+        let line = output.trim();
+        match line.strip_prefix("kvm support: ") {
+            Some("enabled") => Ok(HmpKvm::Enabled),
+            Some("disabled") => Ok(HmpKvm::Disabled),
+            Some("not compiled") => Ok(HmpKvm::NotCompiled),
+            _ => Err(invalid(Self::COMMAND, line)),
+        }
+    }
+}
+
+/// A vCPU listed by `info cpus`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HmpCpu {
+    pub index: u32,
+    /// Whether this is the monitor's current CPU
+    pub current: bool,
+    pub thread_id: Option<u64>,
+    pub halted: bool,
+}
+
+/// `info cpus`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HmpCpus(pub Vec<HmpCpu>);
+
+impl HmpInfo for HmpCpus {
+    const COMMAND: &'static str = "info cpus";
+
+    fn parse(output: &str) -> io::Result<Self> {
+        output.lines().filter(|l| !l.trim().is_empty()).map(|line| {
+            let current = line.starts_with('*');
+            let cpu = line.trim_start_matches('*').trim_start()
+                .strip_prefix("CPU #")
+                .ok_or_else(|| invalid(Self::COMMAND, line))?;
+            let (index, rest) = cpu.split_once(':')
+                .ok_or_else(|| invalid(Self::COMMAND, line))?;
+            let thread_id = rest.split_whitespace()
+                .find_map(|w| w.strip_prefix("thread_id="))
+                .map(|id| id.parse().map_err(|_| invalid(Self::COMMAND, line)))
+                .transpose()?;
+            Ok(HmpCpu {
+                index: index.parse().map_err(|_| invalid(Self::COMMAND, line))?,
+                current,
+                thread_id,
+                halted: rest.contains("(halted)"),
+            })
+        }).collect::<io::Result<_>>().map(HmpCpus)
+    }
+}
+
+/// A memory region in the output of `info mtree`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HmpMemoryRegion {
+    pub start: u64,
+    /// Inclusive
+    pub end: u64,
+    pub priority: i32,
+    /// Such as `i/o`, `ram` or `romd`
+    pub kind: String,
+    pub name: String,
+    /// The aliased region, and the range of it that is mapped
+    pub alias: Option<(String, u64, u64)>,
+    pub enabled: bool,
+    pub subregions: Vec<HmpMemoryRegion>,
+}
+
+impl HmpMemoryRegion {
+    fn parse(line: &str) -> Option<Self> {
+        let range = |range: &str| range.split_once('-').and_then(|(start, end)| Some((
+            u64::from_str_radix(start, 16).ok()?,
+            u64::from_str_radix(end, 16).ok()?,
+        )));
+
+        let (bounds, rest) = line.split_once(" (")?;
+        let (start, end) = range(bounds)?;
+        let (attrs, desc) = rest.split_once("): ")?;
+        let (priority, kind) = attrs.strip_prefix("prio ")?.split_once(", ")?;
+        let (desc, enabled) = match desc.strip_suffix("[disabled]") {
+            Some(desc) => (desc.trim_end(), false),
+            None => (desc, true),
+        };
+        let (name, alias) = match desc.strip_prefix("alias ") {
+            Some(alias) => {
+                let (name, target) = alias.split_once(" @")?;
+                let (target, target_range) = target.split_once(' ')?;
+                let (target_start, target_end) = range(target_range.trim())?;
+                (name, Some((target.into(), target_start, target_end)))
+            },
+            None => (desc, None),
+        };
+
+        Some(HmpMemoryRegion {
+            start,
+            end,
+            priority: priority.parse().ok()?,
+            kind: kind.into(),
+            name: name.into(),
+            alias,
+            enabled,
+            subregions: Vec::new(),
+        })
+    }
+}
+
+/// A tree of regions in `info mtree`, rooted at one or more address spaces or at an aliased region
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HmpMemoryTree {
+    /// Address spaces that share this tree, or the name of the region
+    pub names: Vec<String>,
+    pub is_address_space: bool,
+    pub regions: Vec<HmpMemoryRegion>,
+}
+
+/// `info mtree`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HmpMtree(pub Vec<HmpMemoryTree>);
+
+impl HmpMtree {
+    pub fn address_space(&self, name: &str) -> Option<&HmpMemoryTree> {
+        self.0.iter().find(|t| t.is_address_space && t.names.iter().any(|n| n == name))
+    }
+}
+
+impl HmpInfo for HmpMtree {
+    const COMMAND: &'static str = "info mtree";
+
+    fn parse(output: &str) -> io::Result<Self> {
+        fn insert(regions: &mut Vec<HmpMemoryRegion>, depth: usize, region: HmpMemoryRegion) -> bool {
+            match depth {
+                0 => {
+                    regions.push(region);
+                    true
+                },
+                depth => match regions.last_mut() {
+                    Some(parent) => insert(&mut parent.subregions, depth - 1, region),
+                    None => false,
+                },
+            }
+        }
+
+        let mut trees: Vec<HmpMemoryTree> = Vec::new();
+        let mut continued = false;
+        for line in output.lines() {
+            let (name, is_address_space) = if let Some(name) = line.strip_prefix("address-space: ") {
+                (name, true)
+            } else if let Some(name) = line.strip_prefix("memory-region: ") {
+                (name, false)
+            } else if line.trim().is_empty() {
+                continued = false;
+                continue
+            } else {
+                let indent = line.len() - line.trim_start().len();
+                let region = HmpMemoryRegion::parse(line.trim_start())
+                    .ok_or_else(|| invalid(Self::COMMAND, line))?;
+                let tree = trees.last_mut()
+                    .ok_or_else(|| invalid(Self::COMMAND, line))?;
+                if !insert(&mut tree.regions, (indent / 2).saturating_sub(1), region) {
+                    return Err(invalid(Self::COMMAND, line))
+                }
+                continued = false;
+                continue
+            };
+
+            // consecutive address spaces are printed together when they share a root
+            match trees.last_mut() {
+                Some(tree) if continued && tree.regions.is_empty() && tree.is_address_space == is_address_space =>
+                    tree.names.push(name.into()),
+                _ => trees.push(HmpMemoryTree {
+                    names: vec![name.into()],
+                    is_address_space,
+                    regions: Vec::new(),
+                }),
+            }
+            continued = true;
+        }
+
+        Ok(HmpMtree(trees))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::RunState;
+    use super::{HmpInfo, HmpStatus, HmpCpus, HmpMtree, hmp_output};
+
+    #[test]
+    fn parse_info() {
+        assert_eq!(HmpStatus::parse("VM status: paused (shutdown)\r\n").unwrap(), HmpStatus {
+            running: false,
+            single_step: false,
+            status: Some(RunState::shutdown),
+        });
+        assert!(HmpStatus::parse("VM status: running\r\n").unwrap().running);
+
+        let cpus = HmpCpus::parse("* CPU #0: thread_id=1234\r\n  CPU #1: thread_id=1235 (halted)\r\n").unwrap().0;
+        assert_eq!(cpus.len(), 2);
+        assert!(cpus[0].current && !cpus[1].current && cpus[1].halted);
+        assert_eq!(cpus[1].thread_id, Some(1235));
+
+        let mtree = HmpMtree::parse(concat!(
+            "address-space: cpu-memory-0\r\n",
+            "address-space: memory\r\n",
+            "  0000000000000000-ffffffffffffffff (prio 0, i/o): system\r\n",
+            "    0000000000000000-000000007fffffff (prio 0, ram): alias ram-below-4g @pc.ram 0000000000000000-000000007fffffff\r\n",
+            "    00000000000a0000-00000000000bffff (prio 1, i/o): vga-lowmem\r\n",
+            "    00000000000c0000-00000000000dffff (prio 1, rom): pc.rom [disabled]\r\n",
+            "\r\n",
+            "address-space: I/O\r\n",
+            "  0000000000000000-000000000000ffff (prio 0, i/o): io\r\n",
+            "\r\n",
+            "memory-region: pc.ram\r\n",
+            "  0000000000000000-00000000bfffffff (prio 0, ram): pc.ram\r\n",
+        )).unwrap();
+        assert_eq!(mtree.0.len(), 3);
+        let memory = mtree.address_space("memory").unwrap();
+        assert_eq!(memory.names, ["cpu-memory-0", "memory"]);
+        let system = &memory.regions[0];
+        assert_eq!((system.end, system.subregions.len()), (u64::MAX, 3));
+        assert_eq!(system.subregions[0].alias, Some(("pc.ram".into(), 0, 0x7fffffff)));
+        assert_eq!(system.subregions[0].name, "ram-below-4g");
+        assert!(!system.subregions[2].enabled);
+        assert!(!mtree.0[2].is_address_space);
+
+        assert!(hmp_output("info foo", "unknown command: 'info foo'\r\n".into()).is_err());
+    }
+}
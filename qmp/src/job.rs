@@ -0,0 +1,46 @@
+use std::{error, fmt, io};
+use qapi_spec::Enum;
+use crate::{JobInfo, JobStatus, JobType};
+
+impl JobStatus {
+    /// Whether the job has stopped running, and can only be dismissed
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobStatus::concluded | JobStatus::null)
+    }
+}
+
+impl JobInfo {
+    /// Fails with the job's error, if it concluded with one
+    pub fn result(&self) -> Result<(), JobError> {
+        match &self.error {
+            None => Ok(()),
+            Some(error) => Err(JobError {
+                id: self.id.clone(),
+                job_type: self.type_,
+                error: error.clone(),
+            }),
+        }
+    }
+}
+
+/// The error that a job failed or was aborted with, as reported by `query-jobs`
+#[derive(Debug, Clone)]
+pub struct JobError {
+    pub id: String,
+    pub job_type: JobType,
+    pub error: String,
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} job {} failed: {}", self.job_type.name(), self.id, self.error)
+    }
+}
+
+impl error::Error for JobError { }
+
+impl From<JobError> for io::Error {
+    fn from(e: JobError) -> Self {
+        io::Error::other(e)
+    }
+}
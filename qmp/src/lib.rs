@@ -9,6 +9,18 @@ use serde::{Deserialize, Serialize};
 
 include!(concat!(env!("OUT_DIR"), "/qmp.rs"));
 
+mod schema;
+pub use self::schema::{QmpSchema, QmpSchemaInfo, QmpSchemaMeta, QmpSchemaEnumMember, QmpSchemaMember, QmpSchemaVariant, QmpSchemaAlternative, QmpSchemaType, QmpSchemaCommand};
+
+mod version;
+pub use self::version::{QemuVersion, QemuUnsupported};
+
+mod hmp;
+pub use self::hmp::{hmp_output, HmpInfo, HmpStatus, HmpKvm, HmpCpu, HmpCpus, HmpMemoryRegion, HmpMemoryTree, HmpMtree};
+
+mod job;
+pub use self::job::JobError;
+
 pub type QmpMessageAny = QmpMessage<qapi_spec::Any>;
 
 pub trait QmpCommand: qapi_spec::Command { }
@@ -40,20 +52,48 @@ pub struct QMP {
     pub capabilities: Vec<QmpCapability>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
+#[derive(Debug, Clone)]
 pub enum QmpCapability {
-    #[serde(rename = "oob")]
     OutOfBand,
     Unknown(qapi_spec::Any),
 }
 
+impl Serialize for QmpCapability {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            QmpCapability::OutOfBand => QMPCapability::oob.serialize(serializer),
+            QmpCapability::Unknown(c) => c.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for QmpCapability {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // a unit variant of an untagged enum would only match `null`
+        let c = qapi_spec::Any::deserialize(deserializer)?;
+        Ok(match QMPCapability::deserialize(&c) {
+            Ok(QMPCapability::oob) => QmpCapability::OutOfBand,
+            _ => QmpCapability::Unknown(c),
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QapiCapabilities {
     pub QMP: QMP,
 }
 
+impl QMP {
+    pub fn qemu_version(&self) -> QemuVersion {
+        QemuVersion::from(&self.version)
+    }
+}
+
 impl QapiCapabilities {
+    pub fn qemu_version(&self) -> QemuVersion {
+        self.QMP.qemu_version()
+    }
+
     pub fn supports_oob(&self) -> bool {
         self.QMP.capabilities.iter().any(|c| match c {
             QmpCapability::OutOfBand => true,
@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// An entity described by `query-qmp-schema`
+///
+/// Only commands, events and builtins keep their real names, and other types are referred to by
+/// masked names that can be resolved with [`QmpSchema::get`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QmpSchemaInfo {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+    #[serde(flatten)]
+    pub meta: QmpSchemaMeta,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "meta-type", rename_all = "kebab-case")]
+pub enum QmpSchemaMeta {
+    Builtin {
+        #[serde(rename = "json-type")]
+        json_type: String,
+    },
+    Enum {
+        #[serde(default)]
+        members: Vec<QmpSchemaEnumMember>,
+        /// Deprecated in favour of `members`, and absent from newer versions
+        #[serde(default)]
+        values: Vec<String>,
+    },
+    Array {
+        #[serde(rename = "element-type")]
+        element_type: String,
+    },
+    Object {
+        members: Vec<QmpSchemaMember>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tag: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        variants: Vec<QmpSchemaVariant>,
+    },
+    Alternate {
+        members: Vec<QmpSchemaAlternative>,
+    },
+    Command {
+        #[serde(rename = "arg-type")]
+        arg_type: String,
+        #[serde(rename = "ret-type")]
+        ret_type: String,
+        #[serde(rename = "allow-oob", default)]
+        allow_oob: bool,
+    },
+    Event {
+        #[serde(rename = "arg-type")]
+        arg_type: String,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QmpSchemaEnumMember {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QmpSchemaMember {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// Present, though always null, when the member is optional
+    #[serde(default, deserialize_with = "deserialize_present", skip_serializing_if = "Option::is_none")]
+    pub default: Option<qapi_spec::Any>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+}
+
+fn deserialize_present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<qapi_spec::Any>, D::Error> {
+    qapi_spec::Any::deserialize(deserializer).map(Some)
+}
+
+impl QmpSchemaMember {
+    pub fn is_optional(&self) -> bool {
+        self.default.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QmpSchemaVariant {
+    pub case: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QmpSchemaAlternative {
+    #[serde(rename = "type")]
+    pub type_: String,
+}
+
+/// The result of `query-qmp-schema`, indexed by name
+///
+/// Deserializes directly from the command's return value.
+#[derive(Debug, Clone, Default)]
+pub struct QmpSchema {
+    entities: HashMap<String, QmpSchemaInfo>,
+}
+
+impl QmpSchema {
+    pub fn new<I: IntoIterator<Item=QmpSchemaInfo>>(infos: I) -> Self {
+        Self {
+            entities: infos.into_iter().map(|info| (info.name.clone(), info)).collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<QmpSchemaType<'_>> {
+        self.entities.get(name).map(|info| QmpSchemaType {
+            schema: self,
+            info,
+        })
+    }
+
+    pub fn command(&self, name: &str) -> Option<QmpSchemaCommand<'_>> {
+        self.get(name).and_then(|ty| match ty.info.meta {
+            QmpSchemaMeta::Command { .. } => Some(QmpSchemaCommand(ty)),
+            _ => None,
+        })
+    }
+
+    pub fn has_command(&self, name: &str) -> bool {
+        self.command(name).is_some()
+    }
+
+    /// Whether the command `C` is supported by this QEMU
+    pub fn supports<C: qapi_spec::Command>(&self) -> bool {
+        self.has_command(C::NAME)
+    }
+
+    /// The arguments of an event, if this QEMU emits it
+    pub fn event(&self, name: &str) -> Option<QmpSchemaType<'_>> {
+        self.get(name).and_then(|ty| match ty.info.meta {
+            QmpSchemaMeta::Event { ref arg_type } => self.get(arg_type),
+            _ => None,
+        })
+    }
+
+    pub fn has_event(&self, name: &str) -> bool {
+        self.event(name).is_some()
+    }
+
+    /// Whether `command` exists and accepts the named argument
+    pub fn command_accepts(&self, command: &str, argument: &str) -> bool {
+        self.command(command).and_then(|c| c.argument(argument)).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=QmpSchemaType<'_>> {
+        self.entities.values().map(move |info| QmpSchemaType {
+            schema: self,
+            info,
+        })
+    }
+
+    pub fn commands(&self) -> impl Iterator<Item=QmpSchemaCommand<'_>> {
+        self.iter().filter_map(|ty| match ty.info.meta {
+            QmpSchemaMeta::Command { .. } => Some(QmpSchemaCommand(ty)),
+            _ => None,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for QmpSchema {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<QmpSchemaInfo>::deserialize(deserializer).map(Self::new)
+    }
+}
+
+/// A schema entity, with its type references resolved through the schema
+#[derive(Debug, Copy, Clone)]
+pub struct QmpSchemaType<'a> {
+    schema: &'a QmpSchema,
+    info: &'a QmpSchemaInfo,
+}
+
+impl<'a> QmpSchemaType<'a> {
+    pub fn info(&self) -> &'a QmpSchemaInfo {
+        self.info
+    }
+
+    pub fn name(&self) -> &'a str {
+        &self.info.name
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.info.features.iter().any(|f| f == feature)
+    }
+
+    /// The members of an object, including those of each variant
+    pub fn members(&self) -> impl Iterator<Item=&'a QmpSchemaMember> + 'a {
+        let (members, variants): (&'a [_], &'a [_]) = match self.info.meta {
+            QmpSchemaMeta::Object { ref members, ref variants, .. } => (members, variants),
+            _ => (&[], &[]),
+        };
+        let schema = self.schema;
+        members.iter().chain(variants.iter()
+            .filter_map(move |v| schema.entities.get(&v.type_))
+            .flat_map(|info| match info.meta {
+                QmpSchemaMeta::Object { ref members, .. } => members.iter(),
+                _ => [].iter(),
+            })
+        )
+    }
+
+    pub fn member(&self, name: &str) -> Option<&'a QmpSchemaMember> {
+        self.members().find(|m| m.name == name)
+    }
+
+    /// Resolves the type of an object member
+    pub fn member_type(&self, name: &str) -> Option<QmpSchemaType<'a>> {
+        self.member(name).and_then(|m| self.schema.get(&m.type_))
+    }
+
+    /// The values accepted by an enum, or by each enum alternative of an alternate
+    pub fn enum_values(&self) -> Option<Vec<&'a str>> {
+        match self.info.meta {
+            QmpSchemaMeta::Enum { ref members, ref values } if members.is_empty() =>
+                Some(values.iter().map(|v| &v[..]).collect()),
+            QmpSchemaMeta::Enum { ref members, .. } =>
+                Some(members.iter().map(|m| &m.name[..]).collect()),
+            QmpSchemaMeta::Alternate { ref members } => {
+                let values: Vec<_> = members.iter()
+                    .filter_map(|m| self.schema.get(&m.type_))
+                    .filter_map(|ty| ty.enum_values())
+                    .flatten()
+                    .collect();
+                if values.is_empty() { None } else { Some(values) }
+            },
+            _ => None,
+        }
+    }
+
+    pub fn element_type(&self) -> Option<QmpSchemaType<'a>> {
+        match self.info.meta {
+            QmpSchemaMeta::Array { ref element_type } => self.schema.get(element_type),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct QmpSchemaCommand<'a>(QmpSchemaType<'a>);
+
+impl<'a> QmpSchemaCommand<'a> {
+    pub fn name(&self) -> &'a str {
+        self.0.name()
+    }
+
+    pub fn info(&self) -> &'a QmpSchemaInfo {
+        self.0.info
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.0.has_feature(feature)
+    }
+
+    pub fn allow_oob(&self) -> bool {
+        match self.0.info.meta {
+            QmpSchemaMeta::Command { allow_oob, .. } => allow_oob,
+            _ => false,
+        }
+    }
+
+    pub fn arguments(&self) -> Option<QmpSchemaType<'a>> {
+        match self.0.info.meta {
+            QmpSchemaMeta::Command { ref arg_type, .. } => self.0.schema.get(arg_type),
+            _ => None,
+        }
+    }
+
+    pub fn returns(&self) -> Option<QmpSchemaType<'a>> {
+        match self.0.info.meta {
+            QmpSchemaMeta::Command { ref ret_type, .. } => self.0.schema.get(ret_type),
+            _ => None,
+        }
+    }
+
+    pub fn argument(&self, name: &str) -> Option<&'a QmpSchemaMember> {
+        self.arguments().and_then(|args| args.member(name))
+    }
+
+    /// The values accepted by an enum argument
+    pub fn argument_values(&self, name: &str) -> Option<Vec<&'a str>> {
+        self.arguments()
+            .and_then(|args| args.member_type(name))
+            .and_then(|ty| ty.enum_values())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde::Deserialize;
+    use super::{QmpSchema, QmpSchemaMeta};
+
+    const SCHEMA: &str = r#"[
+        {"name": "block-stream", "meta-type": "command", "arg-type": "0", "ret-type": "1"},
+        {"name": "query-jobs", "meta-type": "command", "arg-type": "1", "ret-type": "[2]"},
+        {"name": "migrate-pause", "meta-type": "command", "arg-type": "1", "ret-type": "1", "allow-oob": true},
+        {"name": "STOP", "meta-type": "event", "arg-type": "1"},
+        {"name": "0", "meta-type": "object", "members": [
+            {"name": "device", "type": "str"},
+            {"name": "speed", "type": "int", "default": null},
+            {"name": "on-error", "type": "3", "default": null},
+            {"name": "mode", "type": "4", "default": null, "features": ["unstable"]}
+        ]},
+        {"name": "1", "meta-type": "object", "members": []},
+        {"name": "[2]", "meta-type": "array", "element-type": "2"},
+        {"name": "2", "meta-type": "object", "members": [{"name": "id", "type": "str"}], "tag": "id",
+            "variants": [{"case": "backup", "type": "5"}]},
+        {"name": "3", "meta-type": "enum", "members": [{"name": "report"}, {"name": "ignore"}], "values": ["report", "ignore"]},
+        {"name": "4", "meta-type": "alternate", "members": [{"type": "6"}, {"type": "str"}]},
+        {"name": "5", "meta-type": "object", "members": [{"name": "sync", "type": "int"}]},
+        {"name": "6", "meta-type": "enum", "values": ["full", "top"]},
+        {"name": "7", "meta-type": "unknown-future-type"},
+        {"name": "str", "meta-type": "builtin", "json-type": "string"},
+        {"name": "int", "meta-type": "builtin", "json-type": "int"}
+    ]"#;
+
+    #[test]
+    fn resolve() {
+        let schema = QmpSchema::deserialize(SCHEMA.parse::<qapi_spec::Any>().unwrap()).unwrap();
+
+        assert!(schema.has_command("block-stream") && !schema.has_command("drive-mirror"));
+        assert!(schema.supports::<crate::query_jobs>());
+        assert!(schema.command("STOP").is_none() && schema.has_event("STOP"));
+        assert_eq!(schema.commands().count(), 3);
+        assert!(schema.command("migrate-pause").unwrap().allow_oob());
+        assert!(matches!(schema.get("7").unwrap().info().meta, QmpSchemaMeta::Unknown));
+
+        let stream = schema.command("block-stream").unwrap();
+        assert!(schema.command_accepts("block-stream", "speed") && !schema.command_accepts("block-stream", "job-id"));
+        assert!(!stream.argument("device").unwrap().is_optional() && stream.argument("speed").unwrap().is_optional());
+        assert!(stream.argument("mode").unwrap().features.contains(&"unstable".into()));
+        assert_eq!(stream.argument_values("on-error").unwrap(), ["report", "ignore"]);
+        assert_eq!(stream.argument_values("mode").unwrap(), ["full", "top"]);
+        assert_eq!(stream.arguments().unwrap().member_type("device").unwrap().name(), "str");
+        assert_eq!(stream.returns().unwrap().members().count(), 0);
+
+        let job = schema.command("query-jobs").unwrap().returns().unwrap().element_type().unwrap();
+        assert_eq!(job.members().map(|m| &m.name[..]).collect::<Vec<_>>(), ["id", "sync"]);
+    }
+}
@@ -0,0 +1,154 @@
+use std::{error, fmt, io, str};
+use crate::{VersionInfo, VersionTriple};
+
+/// A QEMU release, ordered by version number
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct QemuVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub micro: u32,
+}
+
+impl QemuVersion {
+    pub const fn new(major: u32, minor: u32, micro: u32) -> Self {
+        Self {
+            major,
+            minor,
+            micro,
+        }
+    }
+
+    /// Fails with [`QemuUnsupported`] unless this is at least `required`
+    pub fn require(self, required: QemuVersion, what: &str) -> Result<(), QemuUnsupported> {
+        if self >= required {
+            Ok(())
+        } else {
+            Err(QemuUnsupported::new(self, required, what))
+        }
+    }
+
+    /// Picks the alternative with the newest minimum version that this satisfies
+    ///
+    /// Useful for commands that superseded older forms, such as `blockdev-add` over `drive_add`.
+    pub fn choose<T, I: IntoIterator<Item=(QemuVersion, T)>>(self, what: &str, alternatives: I) -> Result<T, QemuUnsupported> {
+        let mut oldest = None;
+        let mut chosen: Option<(QemuVersion, T)> = None;
+        for (required, alternative) in alternatives {
+            oldest = Some(oldest.map_or(required, |v: QemuVersion| v.min(required)));
+            if self >= required && chosen.as_ref().map(|&(v, _)| required > v).unwrap_or(true) {
+                chosen = Some((required, alternative));
+            }
+        }
+
+        match chosen {
+            Some((_, alternative)) => Ok(alternative),
+            None => Err(QemuUnsupported::new(self, oldest.unwrap_or_default(), what)),
+        }
+    }
+}
+
+impl From<&VersionTriple> for QemuVersion {
+    fn from(v: &VersionTriple) -> Self {
+        Self::new(v.major as _, v.minor as _, v.micro as _)
+    }
+}
+
+impl From<&VersionInfo> for QemuVersion {
+    fn from(v: &VersionInfo) -> Self {
+        Self::from(&v.qemu)
+    }
+}
+
+impl fmt::Display for QemuVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.micro)
+    }
+}
+
+impl str::FromStr for QemuVersion {
+    type Err = io::Error;
+
+    /// Parses `major.minor` or `major.minor.micro`, ignoring any package suffix such as that
+    /// printed by `info version`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid QEMU version {:?}", s));
+        let version = s.split_whitespace().next().unwrap_or_default();
+        let mut parts = version.splitn(3, '.').map(|p| p.parse::<u32>().map_err(|_| invalid()));
+        let major = parts.next().ok_or_else(invalid)??;
+        let minor = parts.next().ok_or_else(invalid)??;
+        let micro = parts.next().transpose()?.unwrap_or_default();
+        Ok(Self::new(major, minor, micro))
+    }
+}
+
+/// A command or feature that requires a newer QEMU
+#[derive(Debug, Clone)]
+pub struct QemuUnsupported {
+    pub version: QemuVersion,
+    pub required: QemuVersion,
+    pub what: String,
+}
+
+impl QemuUnsupported {
+    pub fn new<S: Into<String>>(version: QemuVersion, required: QemuVersion, what: S) -> Self {
+        Self {
+            version,
+            required,
+            what: what.into(),
+        }
+    }
+}
+
+impl fmt::Display for QemuUnsupported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is unsupported on QEMU {}, and requires at least {}", self.what, self.version, self.required)
+    }
+}
+
+impl error::Error for QemuUnsupported { }
+
+impl From<QemuUnsupported> for io::Error {
+    fn from(e: QemuUnsupported) -> Self {
+        io::Error::new(io::ErrorKind::Unsupported, e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use super::{QemuVersion, QemuUnsupported};
+
+    #[test]
+    fn parse_and_compare() {
+        let v: QemuVersion = "8.2.1 (qemu-kvm-8.2.1-1.el9)".parse().unwrap();
+        assert_eq!(v, QemuVersion::new(8, 2, 1));
+        assert_eq!("7.2".parse::<QemuVersion>().unwrap(), QemuVersion::new(7, 2, 0));
+        assert_eq!(v.to_string(), "8.2.1");
+        for invalid in ["", "8", "8.x", "8.2.1.4", "(8.2.1)"] {
+            assert!(invalid.parse::<QemuVersion>().is_err(), "{:?}", invalid);
+        }
+
+        assert!(QemuVersion::new(8, 2, 1) > QemuVersion::new(8, 2, 0));
+        assert!(QemuVersion::new(8, 10, 0) > QemuVersion::new(8, 9, 5));
+        assert!(QemuVersion::new(10, 0, 0) > QemuVersion::new(9, 2, 0));
+    }
+
+    #[test]
+    fn require_and_choose() {
+        let v = QemuVersion::new(6, 0, 0);
+        assert!(v.require(QemuVersion::new(6, 0, 0), "x").is_ok());
+        let e = v.require(QemuVersion::new(8, 1, 0), "x").unwrap_err();
+        assert_eq!(e.to_string(), "x is unsupported on QEMU 6.0.0, and requires at least 8.1.0");
+        assert_eq!(io::Error::from(e).kind(), io::ErrorKind::Unsupported);
+
+        let alternatives = || vec![
+            (QemuVersion::new(2, 9, 0), "blockdev"),
+            (QemuVersion::new(1, 0, 0), "drive"),
+            (QemuVersion::new(7, 0, 0), "newer"),
+        ];
+        assert_eq!(v.choose("add", alternatives()).unwrap(), "blockdev");
+        assert_eq!(QemuVersion::new(2, 0, 0).choose("add", alternatives()).unwrap(), "drive");
+        let QemuUnsupported { required, .. } = QemuVersion::new(0, 15, 0).choose("add", alternatives()).unwrap_err();
+        assert_eq!(required, QemuVersion::new(1, 0, 0));
+    }
+}
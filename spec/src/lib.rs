@@ -162,6 +162,20 @@ impl<C> Response<C> {
             Response::Ok(value) => value.id.as_ref(),
         }
     }
+
+    /// Replies to the command identified by `id`
+    pub fn new(result: Result<C, Error>, id: Option<Any>) -> Self {
+        match result {
+            Ok(return_) => Response::Ok(ResponseValue {
+                return_,
+                id,
+            }),
+            Err(e) => Response::Err(Error {
+                id,
+                .. e
+            }),
+        }
+    }
 }
 
 pub trait Command: Serialize + Sync + Send {
@@ -185,6 +199,14 @@ impl<'a, C: Command> Command for &'a mut C {
     const ALLOW_OOB: bool = C::ALLOW_OOB;
 }
 
+/// Marker for commands that may be sent via `exec-oob`
+///
+/// Implemented only by generated commands declared with `allow-oob: true`.
+pub trait OobCommand: Command { }
+
+impl<C: OobCommand> OobCommand for &C { }
+impl<C: OobCommand> OobCommand for &mut C { }
+
 pub trait Event: DeserializeOwned {
     const NAME: &'static str;
 }
@@ -244,6 +266,37 @@ pub struct Error {
 
 pub type CommandResult<C> = Result<<C as Command>::Ok, Error>;
 
+impl Error {
+    pub fn new<S: Into<String>>(class: ErrorClass, desc: S) -> Self {
+        Self {
+            class,
+            desc: desc.into(),
+            id: None,
+        }
+    }
+
+    pub fn command_not_found(name: &str) -> Self {
+        Self::new(ErrorClass::CommandNotFound, format!("The command {} has not been found", name))
+    }
+}
+
+#[doc(hidden)]
+pub fn handle_command<C, F>(arguments: Any, handler: F) -> Result<Any, Error> where
+    C: Command + DeserializeOwned,
+    C::Ok: Serialize,
+    F: FnOnce(C) -> CommandResult<C>,
+{
+    let arguments = match arguments {
+        Any::Null => Any::Object(Default::default()),
+        arguments => arguments,
+    };
+    let command = C::deserialize(arguments)
+        .map_err(|e| Error::new(ErrorClass::GenericError, format!("Invalid arguments for {}: {}", C::NAME, e)))?;
+    handler(command).and_then(|res| serde_json::to_value(res)
+        .map_err(|e| Error::new(ErrorClass::GenericError, e.to_string()))
+    )
+}
+
 fn serialize_command_name<C: Command, S: Serializer>(_: &PhantomData<&'static str>, s: S) -> Result<S::Ok, S::Error> {
     C::NAME.serialize(s)
 }
@@ -265,6 +318,16 @@ pub struct ExecuteOob<C, I = Any> {
     pub id: I,
 }
 
+/// Executes a command by name, whether or not the schema describes it
+#[derive(Debug, Clone, Serialize)]
+pub struct ExecuteRaw<I = Never> {
+    pub execute: String,
+    #[serde(skip_serializing_if = "Any::is_null")]
+    pub arguments: Any,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<I>,
+}
+
 impl<C: Command, I> Execute<C, I> {
     pub fn new(arguments: C, id: Option<I>) -> Self {
         Self {
@@ -297,6 +360,17 @@ impl<C: Command, I> From<C> for Execute<C, I> {
     }
 }
 
+impl<I> ExecuteRaw<I> {
+    /// `arguments` are omitted from the request when null
+    pub fn new<N: Into<String>>(name: N, arguments: Any, id: Option<I>) -> Self {
+        Self {
+            execute: name.into(),
+            arguments,
+            id,
+        }
+    }
+}
+
 impl<C: Command, I> ExecuteOob<C, I> {
     pub fn new(arguments: C, id: I) -> Self {
         Self {